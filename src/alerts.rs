@@ -0,0 +1,74 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::*;
+use crate::utils;
+
+/// Webhook relays answer quickly or not at all; a stuck one only costs a
+/// background task for this long.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .connect_timeout(Duration::from_secs(3))
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new())
+});
+
+/// `created_at` of the last tick alerted on, so a replayed tick stays quiet.
+static LAST_ALERTED: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+
+/// Posts to `ALERT_WEBHOOK_URL` when the buying rate moved at least
+/// `ALERT_THRESHOLD` since the previous tick. Sent in the background, so
+/// a slow or failing webhook never holds up the feed.
+pub fn check_move(old: i64, new: i64, created_at: &str) {
+    let url = match ALERT_WEBHOOK_URL.as_deref() {
+        Some(url) if *ALERT_THRESHOLD > 0 => url,
+        _ => return,
+    };
+    let diff = new - old;
+    if diff.abs() < *ALERT_THRESHOLD {
+        return;
+    }
+    {
+        let mut last = LAST_ALERTED.lock();
+        if *last == created_at {
+            return;
+        }
+        created_at.clone_into(&mut last);
+    }
+
+    let text = format!(
+        "{} Harga beli emas {} {}: {} → {} ({})",
+        if diff > 0 { "🚀" } else { "🔻" },
+        if diff > 0 { "naik" } else { "turun" },
+        utils::format_rupiah(diff.abs()),
+        utils::format_rupiah(old),
+        utils::format_rupiah(new),
+        created_at
+    );
+    // `content` is read by Discord, `text` by Slack; both ignore the rest
+    let payload = serde_json::json!({
+        "content": text,
+        "text": text,
+        "old": old,
+        "new": new,
+        "diff": diff,
+        "created_at": created_at,
+    });
+
+    tokio::spawn(async move {
+        let req = CLIENT
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string());
+        match req.send().await {
+            Ok(resp) if resp.status().is_success() => debug!("🔔 Alert sent for move {:+}", diff),
+            Ok(resp) => warn!("⚠️ Alert webhook answered HTTP {}", resp.status()),
+            Err(e) => warn!("⚠️ Alert webhook failed: {}", e),
+        }
+    });
+}
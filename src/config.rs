@@ -1,32 +1,466 @@
+use ipnet::IpNet;
 use once_cell::sync::Lazy;
 
-pub const MAX_HISTORY: usize = 1441;
 pub const MAX_USD_HISTORY: usize = 11;
 pub const USD_POLL_INTERVAL_MS: u64 = 300;
+/// Ceiling of a USD/IDR source's doubling retry delay after consecutive failures.
+pub const USD_MAX_BACKOFF_MS: u64 = 60_000;
 pub const MAX_CONNECTIONS: usize = 500;
 pub const STATE_CACHE_TTL_MS: u64 = 20;
+pub const TICKER_CACHE_TTL_MS: u64 = 250;
+/// Bounds how stale the 24h window of the `/api/stats` summary can get.
+pub const SUMMARY_CACHE_TTL_MS: u64 = 1000;
 
 pub const MIN_LIMIT: i64 = 0;
 pub const MAX_LIMIT: i64 = 88888;
 pub const RATE_LIMIT_SECONDS: u64 = 5;
 pub const MAX_FAILED_ATTEMPTS: usize = 5;
-pub const BLOCK_DURATION_SECS: u64 = 300;
-
-pub const RATE_LIMIT_WINDOW: u64 = 60;
-pub const RATE_LIMIT_MAX_REQUESTS: usize = 60;
-pub const RATE_LIMIT_STRICT_MAX: usize = 120;
 
 pub const HEARTBEAT_INTERVAL_SECS: u64 = 15;
 pub const WS_TIMEOUT_SECS: u64 = 45;
+pub const WS_MAX_PROJECTED_FIELDS: usize = 32;
+
+fn env_parse<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_bool(key: &str, default: bool) -> bool {
+    match std::env::var(key).as_deref().map(str::trim) {
+        Ok("1") | Ok("true") | Ok("yes") | Ok("on") => true,
+        Ok("0") | Ok("false") | Ok("no") | Ok("off") => false,
+        _ => default,
+    }
+}
+
+fn env_flag(key: &str) -> bool {
+    env_bool(key, false)
+}
+
+/// Expect a PROXY protocol v1/v2 header on every accepted TCP connection.
+pub static PROXY_PROTOCOL: Lazy<bool> = Lazy::new(|| env_flag("PROXY_PROTOCOL"));
+
+/// Global cap on concurrently handled HTTP requests; excess gets a 503.
+/// WebSocket connections are bounded by `MAX_CONNECTIONS` instead.
+pub static MAX_INFLIGHT_REQUESTS: Lazy<usize> =
+    Lazy::new(|| env_parse("MAX_INFLIGHT_REQUESTS", 256).max(1));
+
+/// Number of ticks looked back when deriving the `/api/stats` trend.
+pub static TREND_WINDOW: Lazy<usize> = Lazy::new(|| env_parse("TREND_WINDOW", 10).max(1));
+
+/// Default number of ticks summarised by `/api/volatility`.
+pub static VOLATILITY_WINDOW: Lazy<usize> =
+    Lazy::new(|| env_parse("VOLATILITY_WINDOW", 60).clamp(1, *MAX_HISTORY));
+
+/// `limit_bulan` served until an admin changes it via `/aturTS`.
+pub static INITIAL_LIMIT_BULAN: Lazy<i64> =
+    Lazy::new(|| env_parse("LIMIT_BULAN", 8).clamp(MIN_LIMIT, MAX_LIMIT));
+
+/// Upper bound (bytes) for the serialized state; older history entries are
+/// dropped to fit and `truncated` is set. 0 means unlimited.
+pub static MAX_STATE_BYTES: Lazy<usize> = Lazy::new(|| env_parse("MAX_STATE_BYTES", 0));
+
+/// Log every Nth gold tick at debug level (1 = every tick).
+pub static TICK_LOG_EVERY: Lazy<u64> = Lazy::new(|| env_parse("TICK_LOG_EVERY", 1).max(1));
+
+/// Per-IP request limits, read once at startup.
+pub struct RateLimitConfig {
+    /// Requests per window before `429`, `RATE_LIMIT_MAX_REQUESTS`.
+    pub max_requests: usize,
+    /// Requests per window before the IP is blocked, `RATE_LIMIT_STRICT_MAX`.
+    /// Never below `max_requests`.
+    pub strict_max: usize,
+    /// `RATE_LIMIT_WINDOW`, seconds.
+    pub window_secs: u64,
+    /// Block after too many failed admin/suspicious attempts, `BLOCK_DURATION_SECS`.
+    pub block_secs: u64,
+    /// Block after crossing `strict_max`, `RATE_LIMIT_BLOCK_SECS`.
+    pub strict_block_secs: u64,
+}
+
+pub static RATE_LIMIT: Lazy<RateLimitConfig> = Lazy::new(|| {
+    let max_requests = env_parse("RATE_LIMIT_MAX_REQUESTS", 60usize).max(1);
+    let mut strict_max = env_parse("RATE_LIMIT_STRICT_MAX", 120usize);
+    if strict_max < max_requests {
+        tracing::warn!(
+            "RATE_LIMIT_STRICT_MAX {} is below RATE_LIMIT_MAX_REQUESTS {}, using {}",
+            strict_max,
+            max_requests,
+            max_requests
+        );
+        strict_max = max_requests;
+    }
+    RateLimitConfig {
+        max_requests,
+        strict_max,
+        window_secs: env_parse("RATE_LIMIT_WINDOW", 60u64).max(1),
+        block_secs: env_parse("BLOCK_DURATION_SECS", 300),
+        strict_block_secs: env_parse("RATE_LIMIT_BLOCK_SECS", 600),
+    }
+});
+
+/// `/health/deep` answers 503 once the newest gold tick is older than this.
+pub static FEED_STALE_SECS: Lazy<u64> = Lazy::new(|| env_parse("FEED_STALE_SECS", 120u64).max(1));
+
+/// Age of the last USD/IDR change past which `/health/deep` reports
+/// `usd_fresh: false`. Informational only; it never fails the check.
+pub static USD_STALE_SECS: Lazy<u64> = Lazy::new(|| env_parse("USD_STALE_SECS", 600u64).max(1));
+
+/// Moves at least this large (rupiah) are always logged, regardless of sampling.
+pub static SIGNIFICANT_MOVE: Lazy<i64> = Lazy::new(|| env_parse("SIGNIFICANT_MOVE", 10_000));
+
+/// Buying-rate move (rupiah, tick to tick) that triggers the alert webhook.
+/// 0 disables alerts.
+pub static ALERT_THRESHOLD: Lazy<i64> = Lazy::new(|| env_parse("ALERT_THRESHOLD", 0i64).max(0));
+
+/// Discord/Slack-compatible incoming webhook for `ALERT_THRESHOLD` alerts.
+pub static ALERT_WEBHOOK_URL: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("ALERT_WEBHOOK_URL").ok().filter(|v| !v.trim().is_empty())
+});
+
+/// Plausible rupiah range for buy/sell rates; ticks outside are dropped.
+pub static MIN_RATE: Lazy<i64> = Lazy::new(|| env_parse("MIN_RATE", 100_000));
+pub static MAX_RATE: Lazy<i64> = Lazy::new(|| env_parse("MAX_RATE", 100_000_000));
+
+/// Drop ticks whose selling rate is above the buying rate (negative spread).
+pub static REJECT_INVERTED_SPREAD: Lazy<bool> =
+    Lazy::new(|| env_bool("REJECT_INVERTED_SPREAD", true));
+
+/// Seconds after an IP's first failed attempt during which the lenient
+/// `FAILED_GRACE_MAX_ATTEMPTS` threshold applies. 0 disables the grace window.
+pub static FAILED_GRACE_SECS: Lazy<u64> = Lazy::new(|| env_parse("FAILED_GRACE_SECS", 0));
+
+/// Block threshold inside the grace window.
+pub static FAILED_GRACE_MAX_ATTEMPTS: Lazy<usize> = Lazy::new(|| {
+    env_parse("FAILED_GRACE_MAX_ATTEMPTS", MAX_FAILED_ATTEMPTS * 2).max(MAX_FAILED_ATTEMPTS)
+});
+
+/// Keep Treasury fields other than buy/sell/created_at and expose them as
+/// `extra` on history items.
+pub static PASSTHROUGH_EXTRA_FIELDS: Lazy<bool> = Lazy::new(|| env_flag("PASSTHROUGH_EXTRA_FIELDS"));
+
+/// Seconds between history diff/status self-checks. 0 disables them.
+pub static INTEGRITY_CHECK_SECS: Lazy<u64> = Lazy::new(|| env_parse("INTEGRITY_CHECK_SECS", 300));
+
+/// Rounding for fractional rates: `nearest` (default), `floor` or `ceil`.
+pub static ROUNDING_MODE: Lazy<crate::utils::RoundingMode> = Lazy::new(|| {
+    std::env::var("ROUNDING_MODE")
+        .ok()
+        .and_then(|v| crate::utils::RoundingMode::parse(&v))
+        .unwrap_or(crate::utils::RoundingMode::Nearest)
+});
+
+/// `waktu_display` format, e.g. `%d/%m/%Y %H:%M:%S` (directives `%Y %m %d
+/// %H %M %S %%`, rendered in WIB). Unset keeps the raw `HH:MM:SS` from
+/// `created_at`; an invalid format is ignored with a warning.
+pub static WAKTU_FORMAT: Lazy<Option<String>> = Lazy::new(|| {
+    let fmt = std::env::var("WAKTU_FORMAT").ok().filter(|f| !f.trim().is_empty())?;
+    if crate::utils::valid_time_format(&fmt) {
+        Some(fmt)
+    } else {
+        tracing::warn!("invalid WAKTU_FORMAT {:?}, using HH:MM:SS", fmt);
+        None
+    }
+});
+
+/// Currency of every rate, declared as `currency` in `/api/state`.
+pub static CURRENCY: Lazy<String> = Lazy::new(|| env_label("CURRENCY", "IDR"));
+/// Quantity the rates are quoted per, declared as `unit` in `/api/state`.
+pub static UNIT: Lazy<String> = Lazy::new(|| env_label("UNIT", "gram"));
+
+fn env_label(key: &str, default: &str) -> String {
+    std::env::var(key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Gold history entries kept, `MAX_HISTORY` in 60..=100000. Default 1441,
+/// 24h of one-minute ticks; out-of-range or unparsable values fall back to it.
+pub static MAX_HISTORY: Lazy<usize> = Lazy::new(|| {
+    const DEFAULT: usize = 1441;
+    let raw = match std::env::var("MAX_HISTORY") {
+        Ok(v) => v,
+        Err(_) => return DEFAULT,
+    };
+    match raw.trim().parse::<usize>() {
+        Ok(n) if (60..=100_000).contains(&n) => n,
+        _ => {
+            tracing::warn!("invalid MAX_HISTORY {:?}, using {}", raw, DEFAULT);
+            DEFAULT
+        }
+    }
+});
+
+/// Decimals shown for grams in the profit tiers, clamped to 2..=6.
+pub static PROFIT_GRAM_DECIMALS: Lazy<usize> =
+    Lazy::new(|| env_parse("PROFIT_GRAM_DECIMALS", 4usize).clamp(2, 6));
+
+/// Decimal separator for grams in the profit tiers: `,` (default) or `.`.
+pub static PROFIT_DECIMAL_SEPARATOR: Lazy<char> = Lazy::new(|| {
+    match std::env::var("PROFIT_DECIMAL_SEPARATOR").as_deref().map(str::trim) {
+        Ok(".") => '.',
+        _ => ',',
+    }
+});
+
+/// Minimum ms between WS commands of one type per connection, as
+/// `cmd=ms,...`. Commands not listed are unlimited.
+pub static WS_COMMAND_INTERVALS_MS: Lazy<Vec<(String, u64)>> = Lazy::new(|| {
+    let raw = std::env::var("WS_COMMAND_INTERVALS_MS")
+        .unwrap_or_else(|_| "get_stats=1000,project=200".into());
+    raw.split(',')
+        .filter_map(|pair| {
+            let (cmd, ms) = pair.split_once('=')?;
+            Some((cmd.trim().to_string(), ms.trim().parse().ok()?))
+        })
+        .collect()
+});
+
+/// When history is full, thin runs of flat ticks (buy unchanged from the
+/// previous entry) down to this many, keeping each run's first and last.
+/// 0 disables compaction; values below 2 are raised to 2.
+pub static COMPACT_FLAT_RUNS: Lazy<usize> = Lazy::new(|| match env_parse("COMPACT_FLAT_RUNS", 0usize) {
+    0 => 0,
+    n => n.max(2),
+});
+
+/// Answer every route except `/health` and `/health/live` with a 503
+/// maintenance page. `/health/deep` is not exempt, so readiness checks built
+/// on it take the instance out of rotation for the duration.
+pub static MAINTENANCE: Lazy<bool> = Lazy::new(|| env_flag("MAINTENANCE"));
 
+/// With `MAINTENANCE`, also skip the Treasury/USD/follower loops instead of
+/// keeping the cache warm.
+pub static MAINTENANCE_PAUSE_LOOPS: Lazy<bool> = Lazy::new(|| env_flag("MAINTENANCE_PAUSE_LOOPS"));
+
+/// When > 0, WS broadcasts go out at most once per this many ms, coalescing
+/// every change since the previous one. 0 (default) broadcasts per tick.
+pub static BROADCAST_CADENCE_MS: Lazy<u64> = Lazy::new(|| env_parse("BROADCAST_CADENCE_MS", 0));
+
+/// Time waits for the `history` write lock on the tick path, reported as
+/// `history_write_wait` in `/api/stats` and `/metrics.json`.
+pub static LOCK_METRICS: Lazy<bool> = Lazy::new(|| env_flag("LOCK_METRICS"));
+
+/// Skip recording a tick whose buy and sell both equal the last history entry.
+pub static SKIP_UNCHANGED_TICKS: Lazy<bool> = Lazy::new(|| env_flag("SKIP_UNCHANGED_TICKS"));
+
+/// Max seconds `/api/state` and `/ws` answer 503 after boot while waiting
+/// for the first Treasury tick. 0 disables warmup.
+pub static WARMUP_SECS: Lazy<u64> = Lazy::new(|| env_parse("WARMUP_SECS", 0));
+
+/// Run gold-only: no USD/IDR polling, `usd_idr_history` stays an empty array.
+pub static DISABLE_USD: Lazy<bool> = Lazy::new(|| env_flag("DISABLE_USD"));
+
+/// Base URL of a leader instance. When set this instance runs as a follower:
+/// it polls the leader's `/api/state` instead of connecting to Treasury.
+pub static LEADER_URL: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("LEADER_URL")
+        .ok()
+        .map(|v| v.trim().trim_end_matches('/').to_string())
+        .filter(|v| !v.is_empty())
+});
+
+/// JSON file gold/USD history is saved to and restored from. Unset = no
+/// persistence. Ignored in follower mode.
+pub static DATA_FILE: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("DATA_FILE").ok().filter(|v| !v.trim().is_empty())
+});
+
+/// Seconds between `DATA_FILE` saves; a round is skipped if nothing changed.
+pub static PERSIST_INTERVAL_SECS: Lazy<u64> =
+    Lazy::new(|| env_parse("PERSIST_INTERVAL_SECS", 30u64).max(1));
+
+/// Fixed seed for `rng::rng()`, for reproducible jitter. Unset = OS entropy.
+pub static RNG_SEED: Lazy<Option<u64>> =
+    Lazy::new(|| std::env::var("RNG_SEED").ok().and_then(|v| v.trim().parse().ok()));
+
+/// Follower poll interval against the leader.
+pub static LEADER_POLL_MS: Lazy<u64> = Lazy::new(|| env_parse("LEADER_POLL_MS", 1000).max(100));
+
+/// USD/IDR sources fetched at once each round, in priority order; the first
+/// price wins and the rest are cancelled. 1 = one after another.
+pub static USD_FETCH_CONCURRENCY: Lazy<usize> =
+    Lazy::new(|| env_parse("USD_FETCH_CONCURRENCY", 1usize).max(1));
+
+/// USD/IDR fetch timeouts in seconds as `(total, connect)`, from
+/// `USD_TIMEOUT_SECS` / `USD_CONNECT_TIMEOUT_SECS`. Both must be positive and
+/// connect must not exceed total, otherwise the defaults (30, 10) are used.
+pub static USD_TIMEOUTS: Lazy<(u64, u64)> = Lazy::new(|| {
+    let total = env_parse("USD_TIMEOUT_SECS", 30u64);
+    let connect = env_parse("USD_CONNECT_TIMEOUT_SECS", 10u64);
+    if total == 0 || connect == 0 || connect > total {
+        tracing::warn!(
+            "invalid USD timeouts (total {}s, connect {}s), using 30s/10s",
+            total,
+            connect
+        );
+        return (30, 10);
+    }
+    (total, connect)
+});
+
+/// Delay (ms) before answering blocked IPs, to slow scanners down. 0 = off.
+pub static TARPIT_MS: Lazy<u64> = Lazy::new(|| env_parse("TARPIT_MS", 0));
+/// Max connections held in the tarpit at once; the rest get an immediate 429.
+pub static TARPIT_MAX_CONCURRENT: Lazy<usize> =
+    Lazy::new(|| env_parse("TARPIT_MAX_CONCURRENT", 32));
+
+/// Cross-check every fast JSON build against serde_json (on by default in debug builds).
+pub static VERIFY_SERIALIZER: Lazy<bool> =
+    Lazy::new(|| env_bool("VERIFY_SERIALIZER", cfg!(debug_assertions)));
+
+/// A WS client that lags behind the broadcast channel this many times within
+/// `WS_LAG_WINDOW_SECS` is disconnected; earlier lags are resynced with a
+/// full snapshot.
+pub static WS_LAG_KICK_COUNT: Lazy<u32> = Lazy::new(|| env_parse("WS_LAG_KICK_COUNT", 5u32).max(1));
+/// Seconds over which `WS_LAG_KICK_COUNT` lags are counted, starting at a
+/// connection's first lag.
+pub static WS_LAG_WINDOW_SECS: Lazy<u64> = Lazy::new(|| env_parse("WS_LAG_WINDOW_SECS", 60));
+
+/// New WS connections allowed to build/send their initial snapshot at once.
+pub static WS_HANDSHAKE_CONCURRENCY: Lazy<usize> =
+    Lazy::new(|| env_parse("WS_HANDSHAKE_CONCURRENCY", 16).max(1));
+
+/// Most recent candles a `/ws?candles=` client gets in its initial batch.
+pub static WS_CANDLE_REPLAY: Lazy<usize> = Lazy::new(|| env_parse("WS_CANDLE_REPLAY", 500));
+
+/// Tick keys remembered for dedup; the oldest is evicted beyond this.
+pub static SHOWN_UPDATES_CAP: Lazy<usize> =
+    Lazy::new(|| env_parse("SHOWN_UPDATES_CAP", 5000).max(1));
+
+/// Per-request access log lines (target `access`, info level). On by default.
+pub static ACCESS_LOG: Lazy<bool> = Lazy::new(|| env_bool("ACCESS_LOG", true));
+
+/// Requests slower than this (ms) are logged at warn. 0 disables it.
+pub static SLOW_REQUEST_MS: Lazy<u64> = Lazy::new(|| env_parse("SLOW_REQUEST_MS", 500));
+
+/// Plaintext admin secret. Deprecated: only read when `ADMIN_SECRET_HASH`
+/// is unset.
 pub static SECRET_KEY: Lazy<String> = Lazy::new(|| {
     std::env::var("ADMIN_SECRET").unwrap_or_else(|_| "indonesia".into())
 });
 
-pub const TREASURY_WS_URL: &str =
+/// SHA-256 of the admin secret as 64 hex digits, e.g. from
+/// `printf %s "$SECRET" | sha256sum`. Takes precedence over `ADMIN_SECRET`.
+pub static ADMIN_SECRET_HASH: Lazy<Option<[u8; 32]>> = Lazy::new(|| {
+    let raw = std::env::var("ADMIN_SECRET_HASH").ok().filter(|v| !v.trim().is_empty())?;
+    let hex = raw.trim();
+    let bytes: Option<Vec<u8>> = (0..32)
+        .map(|i| hex.get(i * 2..i * 2 + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect();
+    match bytes.filter(|_| hex.len() == 64).and_then(|b| b.try_into().ok()) {
+        Some(hash) => Some(hash),
+        None => {
+            // Fail closed: no key hashes to all zeros, so admin access stays
+            // shut instead of silently falling back to the default secret
+            tracing::error!("ADMIN_SECRET_HASH is not 64 hex digits, admin endpoints are locked");
+            Some([0u8; 32])
+        }
+    }
+});
+
+/// Token for uptime monitors (`X-Monitor-Token`) that skips all security checks.
+/// Unset or empty disables the bypass.
+pub static MONITOR_TOKEN: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("MONITOR_TOKEN")
+        .ok()
+        .filter(|t| !t.is_empty())
+});
+
+/// Comma-separated CIDRs or bare IPs; invalid entries are skipped with a warning.
+fn env_cidr_list(key: &str) -> Vec<IpNet> {
+    std::env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| {
+            let parsed = s
+                .parse::<IpNet>()
+                .or_else(|_| s.parse::<std::net::IpAddr>().map(IpNet::from));
+            if parsed.is_err() {
+                tracing::warn!("{}: ignoring invalid entry {:?}", key, s);
+            }
+            parsed.ok()
+        })
+        .collect()
+}
+
+/// Peers whose `x-forwarded-for` / `x-real-ip` are believed; everyone else is
+/// identified by the socket address. Unset = loopback only; list the
+/// platform proxy's range (e.g. `10.0.0.0/8`) when it connects from a private
+/// network. Set it empty to trust no peer.
+pub static TRUSTED_PROXIES: Lazy<Vec<IpNet>> = Lazy::new(|| {
+    if std::env::var_os("TRUSTED_PROXIES").is_none() {
+        return ["127.0.0.0/8", "::1/128"]
+            .iter()
+            .filter_map(|s| s.parse().ok())
+            .collect();
+    }
+    env_cidr_list("TRUSTED_PROXIES")
+});
+
+/// IPs allowed to call `/aturTS` at all. Empty = any IP may try with the key.
+pub static ADMIN_ALLOWLIST: Lazy<Vec<IpNet>> = Lazy::new(|| env_cidr_list("ADMIN_ALLOWLIST"));
+
+const DEFAULT_TREASURY_WS_URL: &str =
     "wss://ws-ap1.pusher.com/app/52e99bd2c3c42e577e13?protocol=7&client=js&version=7.0.3&flash=false";
+
+/// Pusher URL built from `PUSHER_APP_KEY`, `PUSHER_CLUSTER`, `PUSHER_PROTOCOL`
+/// and `PUSHER_CLIENT_VERSION`. `PUSHER_HOST` (host[:port]) replaces the
+/// `ws-<cluster>.pusher.com` host and `PUSHER_TLS=0` switches to plain `ws://`,
+/// e.g. for a local mock. Falls back to the stock Treasury URL if invalid.
+pub static TREASURY_WS_URL: Lazy<String> = Lazy::new(|| {
+    let var = |key: &str, default: &str| {
+        std::env::var(key)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| default.to_string())
+    };
+    let key = var("PUSHER_APP_KEY", "52e99bd2c3c42e577e13");
+    let cluster = var("PUSHER_CLUSTER", "ap1");
+    let protocol = var("PUSHER_PROTOCOL", "7");
+    let version = var("PUSHER_CLIENT_VERSION", "7.0.3");
+    let host = var("PUSHER_HOST", &format!("ws-{}.pusher.com", cluster));
+    let scheme = if env_bool("PUSHER_TLS", true) { "wss" } else { "ws" };
+
+    let token = |s: &str| {
+        !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+    let url = format!(
+        "{}://{}/app/{}?protocol={}&client=js&version={}&flash=false",
+        scheme, host, key, protocol, version
+    );
+    let valid = [&key, &cluster, &protocol, &version].iter().all(|s| token(s))
+        && reqwest::Url::parse(&url).is_ok_and(|u| u.host_str().is_some());
+    if !valid {
+        tracing::warn!("invalid Pusher config ({}), using the default Treasury URL", url);
+        return DEFAULT_TREASURY_WS_URL.to_string();
+    }
+    url
+});
+
+/// Treasury REST endpoint listing recent gold rates (a JSON array, or an
+/// object with a `data` array) used to fill the gap left by a reconnect.
+/// Unset disables backfill.
+pub static TREASURY_HISTORY_URL: Lazy<Option<String>> = Lazy::new(|| {
+    std::env::var("TREASURY_HISTORY_URL").ok().filter(|v| !v.trim().is_empty())
+});
+
+/// `BACKFILL=0` keeps live ticks only even when `TREASURY_HISTORY_URL` is set.
+pub static BACKFILL: Lazy<bool> = Lazy::new(|| env_bool("BACKFILL", true));
+
 pub const TREASURY_CHANNEL: &str = "gold-rate";
 pub const TREASURY_EVENT: &str = "gold-rate-event";
+/// Pusher's documented default until `connection_established` says otherwise.
+pub const PUSHER_DEFAULT_ACTIVITY_SECS: u64 = 120;
+pub const PUSHER_PONG_TIMEOUT_SECS: u64 = 30;
+pub const RECONNECT_JITTER_MS: u64 = 500;
 
 pub static SUSPICIOUS_PATHS: Lazy<Vec<&'static str>> = Lazy::new(|| {
     vec![
@@ -34,4 +468,24 @@ pub static SUSPICIOUS_PATHS: Lazy<Vec<&'static str>> = Lazy::new(|| {
         "/api/admin", "/administrator", "/wp-login", "/backup", "/.git",
         "/shell", "/cmd", "/exec", "/eval", "/system", "/passwd", "/etc",
     ]
+});
+
+/// Lowercase User-Agent substrings of known scanners, plus comma-separated
+/// additions from `BLOCKED_USER_AGENTS`.
+pub static SUSPICIOUS_USER_AGENTS: Lazy<Vec<String>> = Lazy::new(|| {
+    let mut list: Vec<String> = [
+        "sqlmap", "nikto", "masscan", "zgrab", "nmap", "nuclei", "dirbuster",
+        "gobuster", "wpscan", "acunetix", "netsparker", "ffuf", "wfuzz",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    list.extend(
+        std::env::var("BLOCKED_USER_AGENTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty()),
+    );
+    list
 });
\ No newline at end of file
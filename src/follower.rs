@@ -0,0 +1,170 @@
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::*;
+use crate::persistence::Snapshot;
+use crate::state::{AppState, GoldEntry, UsdIdrEntry};
+use crate::treasury::status_for;
+use crate::utils;
+use crate::ws_manager::Topics;
+
+/// The parts of a leader's `/api/state` needed to rebuild its history.
+#[derive(serde::Deserialize)]
+struct LeaderState {
+    history: Vec<LeaderItem>,
+    #[serde(default)]
+    usd_idr_history: VecDeque<UsdIdrEntry>,
+    limit_bulan: i64,
+}
+
+#[derive(serde::Deserialize)]
+struct LeaderItem {
+    buying_rate_raw: i64,
+    selling_rate_raw: i64,
+    diff_display: String,
+    created_at: String,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Inverse of `utils::format_diff_display`: `🚀+1.000` is 1000, `🔻-1.000`
+/// is -1000 and anything else (`➖tetap`) is 0.
+fn parse_diff_display(s: &str) -> i64 {
+    let amount = |rest: &str| -> i64 {
+        rest.chars().filter(char::is_ascii_digit).collect::<String>().parse().unwrap_or(0)
+    };
+    if let Some(rest) = s.strip_prefix("🚀") {
+        amount(rest)
+    } else if let Some(rest) = s.strip_prefix("🔻") {
+        -amount(rest)
+    } else {
+        0
+    }
+}
+
+/// Decodes a leader's base `/api/state` into a history snapshot plus its
+/// `limit_bulan`.
+fn decode_state(data: &[u8]) -> Option<(Snapshot, i64)> {
+    let leader: LeaderState = serde_json::from_slice(data).ok()?;
+    let history: VecDeque<GoldEntry> = leader
+        .history
+        .into_iter()
+        .map(|item| {
+            let diff = parse_diff_display(&item.diff_display);
+            GoldEntry {
+                buying_rate: item.buying_rate_raw,
+                selling_rate: item.selling_rate_raw,
+                status: status_for(diff).to_string(),
+                diff,
+                created_at: item.created_at,
+                source: item.source.unwrap_or_else(|| crate::state::SOURCE_LIVE.into()),
+                extra: item.extra,
+            }
+        })
+        .collect();
+    let last_buy = history.back().map(|e| e.buying_rate);
+    let snap = Snapshot { history, usd_idr_history: leader.usd_idr_history, last_buy };
+    Some((snap, leader.limit_bulan))
+}
+
+async fn fetch_state(client: &reqwest::Client, url: &str) -> Option<Bytes> {
+    let resp = client.get(url).send().await.ok()?;
+    if resp.status() != 200 {
+        return None;
+    }
+    resp.bytes().await.ok()
+}
+
+/// Follower replacement for `treasury_ws_loop`: polls the leader's
+/// `/api/state`, with and without profit tiers, and republishes them
+/// whenever either changes.
+pub async fn follower_loop(state: Arc<AppState>) {
+    let leader = match LEADER_URL.as_deref() {
+        Some(url) => url,
+        None => return,
+    };
+    let base_url = format!("{}/api/state", leader);
+    let profit_url = format!("{}/api/state?profit=true", leader);
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .connect_timeout(Duration::from_secs(5))
+        .gzip(true)
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new());
+
+    let mut reachable = true;
+    loop {
+        let fetched = match fetch_state(&client, &base_url).await {
+            Some(base) => fetch_state(&client, &profit_url).await.map(|profit| (base, profit)),
+            None => None,
+        };
+        match fetched {
+            Some((base, profit)) => {
+                if !reachable {
+                    info!("✅ Leader reachable again");
+                    reachable = true;
+                }
+                let changed = base != state.cached_snapshot().data
+                    || profit != state.cached_profit_snapshot().data;
+                match decode_state(&base) {
+                    Some((mirror, limit)) if changed => {
+                        // Leader state moves with its feed, so this keeps /health/deep meaningful here
+                        state.last_tick_at.store(utils::current_timestamp(), Ordering::Relaxed);
+                        state.store_external_state(base, profit, mirror, limit);
+                        state.broadcast_state(Topics::ALL);
+                    }
+                    Some(_) => {}
+                    None => warn!("⚠️ Leader state from {} does not parse", base_url),
+                }
+            }
+            None if reachable => {
+                warn!("⚠️ Leader fetch failed: {}", base_url);
+                reachable = false;
+            }
+            None => {}
+        }
+
+        tokio::time::sleep(Duration::from_millis(*LEADER_POLL_MS)).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leader_state_decodes_into_history() {
+        let body = r#"{"history":[
+            {"buying_rate_raw":1500000,"selling_rate_raw":1480000,"diff_display":"➖tetap",
+             "created_at":"2024-01-01 10:00:00"},
+            {"buying_rate_raw":1502000,"selling_rate_raw":1482000,
+             "diff_display":"🚀+2.000","created_at":"2024-01-01 10:01:00",
+             "source":"backfill"},
+            {"buying_rate_raw":1501000,"selling_rate_raw":1481000,
+             "diff_display":"🔻-1.000","created_at":"2024-01-01 10:02:00"}
+        ],"usd_idr_history":[],"limit_bulan":8}"#;
+        let (snap, limit) = decode_state(body.as_bytes()).expect("leader state decodes");
+
+        assert_eq!(limit, 8);
+        assert_eq!(snap.last_buy, Some(1_501_000));
+        let diffs: Vec<i64> = snap.history.iter().map(|e| e.diff).collect();
+        assert_eq!(diffs, [0, 2_000, -1_000]);
+        let statuses: Vec<&str> = snap.history.iter().map(|e| e.status.as_str()).collect();
+        assert_eq!(statuses, ["➖", "🚀", "🔻"]);
+        assert_eq!(snap.history[0].source, crate::state::SOURCE_LIVE);
+        assert_eq!(snap.history[1].source, crate::state::SOURCE_BACKFILL);
+    }
+
+    #[test]
+    fn garbage_leader_state_is_rejected() {
+        assert!(decode_state(b"<html>").is_none());
+    }
+}
@@ -1,64 +1,161 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        Path, Query, State, WebSocketUpgrade,
+        ws::{rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket},
+        ConnectInfo, Extension, Path, Query, State, WebSocketUpgrade,
     },
     http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
-    response::{Html, IntoResponse, Response},
-    routing::{any, get},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{any, get, post},
     Router,
 };
 use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use subtle::ConstantTimeEq;
+use tracing::{debug, info};
 
+use crate::api_error::ApiError;
 use crate::config::*;
-use crate::state::AppState;
-use crate::template::HTML_TEMPLATE;
+use crate::proxy_protocol::ProxiedAddr;
+use crate::security::{ip_in_list, resolve_client_ip, verify_admin_key};
+use crate::state::{AppState, GoldEntry, HistoryFilter, ProfitTier, ProfitTierSet, TickerFields};
+use crate::template::{HTML_TEMPLATE, STATIC_PAGES};
 use crate::utils;
+use crate::ws_manager::{Frame, Topics, WsMode};
 
 #[derive(serde::Deserialize)]
 pub struct LimitQuery {
     key: Option<String>,
 }
 
-#[inline]
-fn ip_from_headers(h: &HeaderMap) -> &str {
-    if let Some(v) = h.get("x-forwarded-for") {
-        if let Ok(s) = v.to_str() {
-            if let Some(f) = s.split(',').next() {
-                return f.trim();
-            }
-        }
-    }
-    if let Some(v) = h.get("x-real-ip") {
-        if let Ok(s) = v.to_str() {
-            return s.trim();
-        }
-    }
-    "unknown"
+#[derive(serde::Deserialize)]
+pub struct WsQuery {
+    mode: Option<String>,
+    /// Include `jt10`..`jt50` in full- and append-mode frames.
+    #[serde(default)]
+    profit: bool,
+    /// Topics to receive, e.g. `usd` or `gold,usd`. Unset = everything.
+    /// `usd` alone gets `AppState::usd_state` instead of the full state,
+    /// except in patch mode, whose patches apply to the full state.
+    channels: Option<String>,
+    /// `1`: send full-state frames gzip-compressed (starting `1f 8b`) in
+    /// full, append and compact modes. Smaller frames stay plain JSON.
+    compress: Option<String>,
+    /// Candle width (`1m`, `5m`, `15m`, `1h`, `4h`, `1d` or seconds): the
+    /// recent candles follow the first frame, then each gold tick is followed
+    /// by the candle it landed in.
+    candles: Option<String>,
+}
+
+/// Per-connection choices made from a `/ws` query.
+#[derive(Clone, Copy)]
+struct WsOptions {
+    mode: WsMode,
+    profit: bool,
+    topics: Topics,
+    compress: bool,
+    candles: Option<u64>,
+}
+
+/// Candle width in seconds from a `candles=` value, within the same
+/// 60..=86400 bounds as `/api/candles`.
+fn parse_candle_interval(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.char_indices().last()? {
+        (i, 'm') => (&s[..i], 60),
+        (i, 'h') => (&s[..i], 3600),
+        (i, 'd') => (&s[..i], 86400),
+        _ => (s, 1),
+    };
+    let secs = num.parse::<u64>().ok()?.checked_mul(unit)?;
+    (60..=86400).contains(&secs).then_some(secs)
+}
+
+#[derive(serde::Deserialize)]
+pub struct StateQuery {
+    #[serde(default)]
+    profit: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct WindowQuery {
+    n: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CandleQuery {
+    interval: Option<u64>,
 }
 
-static CACHE_HEADERS: &[(header::HeaderName, &str)] = &[];
+#[derive(serde::Deserialize)]
+pub struct TickerQuery {
+    fields: Option<String>,
+}
+
+/// Client IP for handlers, resolved like the security middleware does.
+#[inline]
+fn client_ip(
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    h: &HeaderMap,
+) -> String {
+    resolve_client_ip(proxied.map(|p| p.0 .0), peer.map(|p| p.0), h)
+}
 
 pub fn routes() -> Router<Arc<AppState>> {
-    Router::new()
+    let pages = STATIC_PAGES.iter().fold(Router::new(), |router, &(path, html)| {
+        router.route(path, get(move || static_page(html)))
+    });
+
+    pages
         .route("/", get(index))
         .route("/health", get(health))
+        .route("/health/live", get(health))
+        .route("/health/deep", get(health_deep))
         .route("/api/state", get(get_state))
-        .route("/ws", get(ws_handler))
+        .route("/api/stats", get(get_stats))
+        .route("/metrics.json", get(get_metrics_json))
+        .route("/api/ticker", get(get_ticker))
+        .route("/api/connections", get(get_connections))
+        .route("/api/schema", get(get_schema))
+        .route("/api/volatility", get(get_volatility))
+        .route("/api/history", get(get_history))
+        .route("/api/candles", get(get_candles))
+        .route("/api/history.ndjson", get(get_history_ndjson))
+        .route("/api/export.jsonl", get(export_jsonl))
+        .route("/api/export.csv", get(export_csv))
+        .route("/api/history/offset/:n", get(get_history_offset))
         .route("/aturTS/:value", get(set_limit))
+        .route("/aturTesWS", get(ws_selftest))
+        .route("/aturTier", post(set_tiers))
         .fallback(any(catch_all))
 }
 
+/// Long-lived streaming routes are kept apart so they bypass the HTTP
+/// concurrency limit.
+pub fn ws_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/ws", get(ws_handler))
+        .route("/sse", get(sse_handler))
+        .route("/ws/security", get(security_ws_handler))
+}
+
+pub async fn overloaded(_err: tower::BoxError) -> Response {
+    ApiError::unavailable("overloaded", "Server sedang sibuk").retry_after(1).into_response()
+}
+
 async fn index() -> Response {
     (
         StatusCode::OK,
         [
             (header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8")),
-            (header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=300, stale-while-revalidate=60")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=3600, stale-while-revalidate=300")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
             (header::HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff")),
             (header::HeaderName::from_static("referrer-policy"), HeaderValue::from_static("strict-origin-when-cross-origin")),
         ],
@@ -67,140 +164,1064 @@ async fn index() -> Response {
         .into_response()
 }
 
+async fn static_page(html: &'static str) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=3600, stale-while-revalidate=300")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+            (header::HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff")),
+        ],
+        html,
+    )
+        .into_response()
+}
+
 async fn health() -> &'static str {
     "ok"
 }
 
-async fn get_state(State(state): State<Arc<AppState>>) -> Response {
-    let data = state.get_cached_state();
+/// Readiness for load balancers: 503 when no gold tick arrived within
+/// `FEED_STALE_SECS`, so a stuck instance drops out of rotation.
+async fn health_deep(State(state): State<Arc<AppState>>) -> Response {
+    let since_update = AppState::seconds_since(&state.last_tick_at);
+    let feed_ok = since_update.is_some_and(|s| s <= *FEED_STALE_SECS);
+    let usd_fresh = !*DISABLE_USD
+        && AppState::seconds_since(&state.last_usd_update_at).is_some_and(|s| s <= *USD_STALE_SECS);
+
+    let status = if feed_ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (
+        status,
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        axum::Json(serde_json::json!({
+            "status": if feed_ok { "ok" } else { "stale" },
+            "seconds_since_update": since_update,
+            "stale_after_secs": *FEED_STALE_SECS,
+            "connections": state.ws_manager.count(),
+            "usd_fresh": usd_fresh,
+        })),
+    )
+        .into_response()
+}
+
+fn warming_up() -> Response {
+    ApiError::unavailable("warming_up", "Menunggu data pertama").retry_after(5).into_response()
+}
+
+/// Response extension telling the compression layer to leave the body alone.
+#[derive(Clone, Copy)]
+pub struct Precompressed;
+
+/// Picks `br` or `gzip` from Accept-Encoding, ignoring codings with `q=0`.
+fn preferred_encoding(headers: &HeaderMap) -> Option<&'static str> {
+    let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+    let accepted = |name: &str| {
+        accept.split(',').any(|part| {
+            let mut it = part.split(';').map(str::trim);
+            it.next().is_some_and(|n| n.eq_ignore_ascii_case(name))
+                && !it.any(|p| matches!(p, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+        })
+    };
+    if accepted("br") {
+        Some("br")
+    } else if accepted("gzip") {
+        Some("gzip")
+    } else {
+        None
+    }
+}
+
+async fn get_state(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StateQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_warming_up() {
+        return warming_up();
+    }
+    // Compressed once per cache version instead of per request
+    let snap = if query.profit {
+        state.cached_profit_snapshot()
+    } else {
+        state.cached_snapshot()
+    };
+    let (body, encoding) = match preferred_encoding(&headers) {
+        Some("br") => (snap.brotli().clone(), Some("br")),
+        Some(_) => (snap.gzip().clone(), Some("gzip")),
+        None => (snap.data.clone(), None),
+    };
+
+    let mut resp = (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/json")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        body,
+    )
+        .into_response();
+    if let Some(encoding) = encoding {
+        resp.headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+    resp.extensions_mut().insert(Precompressed);
+    resp
+}
+
+async fn get_ticker(State(state): State<Arc<AppState>>, Query(query): Query<TickerQuery>) -> Response {
+    let fields = match query.fields.as_deref().map(TickerFields::parse) {
+        None => TickerFields::ALL,
+        Some(Ok(fields)) => fields,
+        Some(Err(name)) => {
+            return ApiError::invalid_value(format!("Field tidak dikenal: {}", name)).into_response()
+        }
+    };
     (
         StatusCode::OK,
         [
             (header::CONTENT_TYPE, HeaderValue::from_static("application/json")),
             (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        state.get_ticker(fields),
+    )
+        .into_response()
+}
+
+/// Open `/ws` and `/sse` streams against `MAX_CONNECTIONS`; one atomic load.
+async fn get_connections(State(state): State<Arc<AppState>>) -> Response {
+    let connections = state.ws_manager.count();
+    (
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate"))],
+        axum::Json(serde_json::json!({
+            "connections": connections,
+            "max": MAX_CONNECTIONS,
+            "capacity_pct": connections as f64 * 100.0 / MAX_CONNECTIONS as f64,
+        })),
+    )
+        .into_response()
+}
+
+async fn get_stats(State(state): State<Arc<AppState>>) -> Response {
+    let window = *TREND_WINDOW;
+    let (trend, trend_change) = state.trend(window);
+    let feed_lag = *state.feed_lag.lock();
+    let usd_fetch = *state.usd_fetch.lock();
+    let (shown_size, shown_evictions) = {
+        let shown = state.shown_updates.lock();
+        (shown.len(), shown.evictions)
+    };
+    let summary = state.summary();
+    let current_buy = state
+        .has_last_buy
+        .load(Ordering::Relaxed)
+        .then(|| state.last_buy.load(Ordering::Relaxed));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        axum::Json(serde_json::json!({
+            "count": summary.count,
+            "oldest_created_at": summary.oldest_created_at,
+            "newest_created_at": summary.newest_created_at,
+            "current_buy": current_buy,
+            "current_sell": summary.current_sell,
+            "min_buy_24h": summary.min_buy_24h,
+            "max_buy_24h": summary.max_buy_24h,
+            "avg_diff": summary.avg_diff,
+            "connections": state.ws_manager.count(),
+            "trend": trend,
+            "trend_change": trend_change,
+            "trend_window": window,
+            "feed_lag": feed_lag,
+            "usd_fetch": usd_fetch,
+            "integrity_corrections": state.integrity_corrections.load(Ordering::Relaxed),
+            "ticks_total": state.ticks_total.load(Ordering::Relaxed),
+            "usd_updates_total": state.usd_updates_total.load(Ordering::Relaxed),
+            "history_write_wait": state.history_lock_stats(),
+            "seconds_since_last_tick": AppState::seconds_since(&state.last_tick_at),
+            "seconds_since_last_usd_update": AppState::seconds_since(&state.last_usd_update_at),
+            "dedup": {
+                "size": shown_size,
+                "cap": *SHOWN_UPDATES_CAP,
+                "evictions": shown_evictions,
+            },
+        })),
+    )
+        .into_response()
+}
+
+/// JSON Schema of the `/api/state` body; keep in step with `write_item`.
+const STATE_SCHEMA: &str = include_str!("schema/state.json");
+
+async fn get_schema() -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/schema+json")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=3600")),
         ],
-        data,
+        STATE_SCHEMA,
     )
         .into_response()
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
-    ws.on_upgrade(move |socket| handle_ws(socket, state))
+async fn get_metrics_json(State(state): State<Arc<AppState>>) -> Response {
+    (
+        StatusCode::OK,
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        axum::Json(state.metrics()),
+    )
+        .into_response()
 }
 
-async fn handle_ws(socket: WebSocket, state: Arc<AppState>) {
+async fn get_volatility(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<WindowQuery>,
+) -> Response {
+    let window = query.n.unwrap_or(*VOLATILITY_WINDOW);
+    (
+        StatusCode::OK,
+        [
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        axum::Json(state.volatility(window)),
+    )
+        .into_response()
+}
+
+async fn get_history_offset(
+    State(state): State<Arc<AppState>>,
+    Path(n): Path<usize>,
+    Query(query): Query<StateQuery>,
+) -> Response {
+    match state.entry_at_offset(n) {
+        Some(entry) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, HeaderValue::from_static("application/json")),
+                (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+                (header::VARY, HeaderValue::from_static("accept-encoding")),
+            ],
+            state.item_json(&entry, query.profit),
+        )
+            .into_response(),
+        None => ApiError::not_found("Offset di luar jangkauan").into_response(),
+    }
+}
+
+/// Entries between `from` and `to` (unix seconds), newest first, in the
+/// `/api/state` item shape; `profit=true` adds the tiers like there.
+async fn get_history(
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<HistoryFilter>,
+    Query(query): Query<StateQuery>,
+) -> Response {
+    if !filter.is_valid() {
+        return ApiError::invalid_value("from harus <= to").into_response();
+    }
+
+    let entries = state.filtered_history(&filter);
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/json")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        state.items_json(&entries, query.profit),
+    )
+        .into_response()
+}
+
+/// OHLC candles of the buying rate, `interval` seconds wide (default 300).
+async fn get_candles(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CandleQuery>,
+) -> Response {
+    let interval = query.interval.unwrap_or(300);
+    if !(60..=86400).contains(&interval) {
+        return ApiError::invalid_value("interval harus antara 60 dan 86400 detik").into_response();
+    }
+    (
+        StatusCode::OK,
+        [
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        axum::Json(state.candles(interval)),
+    )
+        .into_response()
+}
+
+async fn get_history_ndjson(
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<HistoryFilter>,
+) -> Response {
+    if !filter.is_valid() {
+        return ApiError::invalid_value("from harus <= to").into_response();
+    }
+
+    // Entries are copied out under the lock; serialization happens while streaming
+    let entries = state.filtered_history(&filter);
+    let lines = futures_util::stream::iter(entries.into_iter().map(|e| {
+        let mut line = serde_json::to_vec(&e).unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, std::convert::Infallible>(Bytes::from(line))
+    }));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/x-ndjson")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache, must-revalidate")),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ],
+        axum::body::Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Admin gate shared by the export downloads. Entries are copied out under
+/// the lock; serialization happens while streaming.
+fn export_entries(state: &AppState, ip: &str, key: Option<&str>) -> Result<Vec<GoldEntry>, ApiError> {
+    if state.is_ip_blocked(ip) {
+        return Err(ApiError::ip_blocked());
+    }
+    check_admin(state, ip, key)?;
+    Ok(state.history.read().iter().cloned().collect())
+}
+
+/// Quotes a CSV field per RFC 4180 when it holds a comma, quote or line break.
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+/// Whole history, oldest first, as a CSV download with raw numbers. Admin only.
+async fn export_csv(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LimitQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    let ip = &client_ip(proxied, peer, &headers);
+    let entries = match export_entries(&state, ip, query.key.as_deref()) {
+        Ok(entries) => entries,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let header_row = Bytes::from_static(b"created_at,buying_rate,selling_rate,diff,status\r\n");
+    let rows = entries.into_iter().map(|e| {
+        Bytes::from(format!(
+            "{},{},{},{},{}\r\n",
+            csv_field(&e.created_at),
+            e.buying_rate,
+            e.selling_rate,
+            e.diff,
+            csv_field(&e.status)
+        ))
+    });
+    let body = futures_util::stream::iter(
+        std::iter::once(header_row).chain(rows).map(Ok::<_, std::convert::Infallible>),
+    );
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8")),
+            (header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=\"history.csv\"")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+        axum::body::Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// One line of `/api/export.jsonl`.
+#[derive(serde::Serialize)]
+struct ExportLine<'a> {
+    buying_rate: i64,
+    selling_rate: i64,
+    status: &'a str,
+    diff: i64,
+    created_at: &'a str,
+}
+
+/// Whole history, oldest first, as a JSON Lines download. Admin only.
+async fn export_jsonl(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LimitQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    let ip = &client_ip(proxied, peer, &headers);
+    let entries = match export_entries(&state, ip, query.key.as_deref()) {
+        Ok(entries) => entries,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let lines = futures_util::stream::iter(entries.into_iter().map(|e| {
+        let mut line = serde_json::to_vec(&ExportLine {
+            buying_rate: e.buying_rate,
+            selling_rate: e.selling_rate,
+            status: &e.status,
+            diff: e.diff,
+            created_at: &e.created_at,
+        })
+        .unwrap_or_default();
+        line.push(b'\n');
+        Ok::<_, std::convert::Infallible>(Bytes::from(line))
+    }));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/jsonl")),
+            (header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=\"history.jsonl\"")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+        axum::body::Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Answer for plain HTTP requests to `/ws`, e.g. a browser opening it directly.
+fn not_a_websocket(headers: &HeaderMap) -> Response {
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+
+    if wants_json {
+        let mut resp = ApiError::new(
+            StatusCode::UPGRADE_REQUIRED,
+            "websocket_required",
+            "/ws adalah endpoint WebSocket",
+        )
+        .into_response();
+        resp.headers_mut().insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        return resp;
+    }
+
+    (
+        StatusCode::UPGRADE_REQUIRED,
+        [
+            (header::UPGRADE, HeaderValue::from_static("websocket")),
+            (header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8")),
+        ],
+        "<!DOCTYPE html><html><body><p><code>/ws</code> adalah endpoint WebSocket \
+         dan tidak bisa dibuka langsung di browser.</p><p><a href=\"/\">Kembali ke halaman utama</a></p></body></html>",
+    )
+        .into_response()
+}
+
+async fn ws_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<WsQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(_) => return not_a_websocket(&headers),
+    };
+    if state.is_warming_up() {
+        return warming_up();
+    }
+    let mode = WsMode::parse(query.mode.as_deref());
+    let ip = client_ip(proxied, peer, &headers);
+    let profit = query.profit && matches!(mode, WsMode::Full | WsMode::Append);
+    let topics = Topics::parse(query.channels.as_deref());
+    let compress = matches!(query.compress.as_deref(), Some("1" | "true")) && mode != WsMode::Patch;
+    let candles = match query.candles.as_deref() {
+        Some(s) => match parse_candle_interval(s) {
+            Some(interval) => Some(interval),
+            None => {
+                return ApiError::invalid_value("candles harus antara 1m dan 1d").into_response()
+            }
+        },
+        None => None,
+    };
+    let opts = WsOptions { mode, profit, topics, compress, candles };
+    ws.on_upgrade(move |socket| handle_ws(socket, state, opts, ip))
+}
+
+/// Releases an SSE stream's slot in the shared connection budget when the
+/// client goes away and the stream is dropped.
+struct SseSlot(Arc<AppState>);
+
+impl Drop for SseSlot {
+    fn drop(&mut self) {
+        self.0.ws_manager.unsubscribe();
+    }
+}
+
+/// Full-mode state frames as server-sent events, for networks that break
+/// WebSocket upgrades. Counts against `MAX_CONNECTIONS` like `/ws`.
+async fn sse_handler(
+    State(state): State<Arc<AppState>>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if state.is_warming_up() {
+        return warming_up();
+    }
+    let rx = match state.ws_manager.subscribe() {
+        Some(rx) => rx,
+        None => return ApiError::unavailable("at_capacity", "Server penuh").retry_after(30).into_response(),
+    };
+    info!("SSE open ip={}", client_ip(proxied, peer, &headers));
+
+    let event = |data: &Bytes| Ok::<_, std::convert::Infallible>(Event::default().data(String::from_utf8_lossy(data)));
+    let first = event(&state.cached_snapshot().data);
+    let slot = SseSlot(state);
+    let updates = futures_util::stream::unfold((rx, slot), move |(mut rx, slot)| async move {
+        loop {
+            match rx.recv().await {
+                // Heartbeats and probes are covered by the SSE keep-alive
+                Ok(frame) if frame.version.is_none() => continue,
+                Ok(frame) => return Some((event(&frame.full), (rx, slot))),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                    let snap = slot.0.cached_snapshot();
+                    return Some((event(&snap.data), (rx, slot)));
+                }
+                Err(_) => return None,
+            }
+        }
+    });
+
+    Sse::new(futures_util::stream::once(async move { first }).chain(updates))
+        .keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(HEARTBEAT_INTERVAL_SECS)))
+        .into_response()
+}
+
+/// Why a WebSocket connection ended, for the disconnect log line.
+#[derive(Clone, Copy)]
+enum DisconnectReason {
+    ClientClose,
+    Timeout,
+    SendFailed,
+    Protocol,
+    ServerShutdown,
+    Lagged,
+}
+
+impl DisconnectReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            DisconnectReason::ClientClose => "client_close",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::SendFailed => "send_failed",
+            DisconnectReason::Protocol => "protocol",
+            DisconnectReason::ServerShutdown => "server_shutdown",
+            DisconnectReason::Lagged => "lagged",
+        }
+    }
+}
+
+/// Lag events of one connection within a `WS_LAG_WINDOW_SECS` window
+/// opened by the first of them.
+#[derive(Default)]
+struct LagWindow {
+    started: Option<std::time::Instant>,
+    count: u32,
+}
+
+impl LagWindow {
+    /// Counts a lag; true once the connection has lagged too often.
+    fn record(&mut self) -> bool {
+        let window = std::time::Duration::from_secs(*WS_LAG_WINDOW_SECS);
+        match self.started {
+            Some(t) if t.elapsed() < window => self.count += 1,
+            _ => {
+                self.started = Some(std::time::Instant::now());
+                self.count = 1;
+            }
+        }
+        self.count >= *WS_LAG_KICK_COUNT
+    }
+}
+
+/// Inbound WebSocket command, e.g. `{"cmd":"project","fields":["buying_rate_raw"]}`.
+#[derive(serde::Deserialize)]
+struct WsCommand {
+    cmd: String,
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+type Projection = Option<Arc<[String]>>;
+
+/// Re-serializes a full-state frame keeping only `fields` in each history item.
+///
+/// This runs per connection on every broadcast, so a projecting client costs a
+/// JSON parse + write per update instead of sharing the prebuilt blob. Only
+/// clients that send a `project` command pay for it.
+fn project_state(full: &[u8], fields: &[String]) -> Option<Bytes> {
+    let mut v: serde_json::Value = serde_json::from_slice(full).ok()?;
+    if let Some(items) = v.get_mut("history").and_then(|h| h.as_array_mut()) {
+        for item in items {
+            if let Some(obj) = item.as_object_mut() {
+                obj.retain(|k, _| fields.iter().any(|f| f == k));
+            }
+        }
+    }
+    serde_json::to_vec(&v).ok().map(Bytes::from)
+}
+
+/// Per-connection minimum spacing between commands of the same type,
+/// from `WS_COMMAND_INTERVALS_MS`.
+#[derive(Default)]
+struct CommandLimiter {
+    last: HashMap<String, std::time::Instant>,
+}
+
+impl CommandLimiter {
+    fn allow(&mut self, cmd: &str) -> bool {
+        let interval = match WS_COMMAND_INTERVALS_MS.iter().find(|(name, _)| name == cmd) {
+            Some(&(_, ms)) if ms > 0 => std::time::Duration::from_millis(ms),
+            _ => return true,
+        };
+        let now = std::time::Instant::now();
+        match self.last.get(cmd) {
+            Some(&prev) if now.duration_since(prev) < interval => false,
+            _ => {
+                self.last.insert(cmd.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+fn handle_command(
+    text: &str,
+    state: &AppState,
+    limiter: &mut CommandLimiter,
+    projection: &tokio::sync::watch::Sender<Projection>,
+    reply: &tokio::sync::mpsc::Sender<Bytes>,
+) {
+    let cmd = match serde_json::from_str::<WsCommand>(text) {
+        Ok(cmd) => cmd,
+        Err(_) => return,
+    };
+    if !limiter.allow(&cmd.cmd) {
+        let msg = serde_json::json!({"error": "rate_limited", "cmd": cmd.cmd});
+        let _ = reply.try_send(Bytes::from(msg.to_string()));
+        return;
+    }
+    if cmd.cmd == "get_stats" {
+        let msg = serde_json::json!({"type": "stats", "stats": state.metrics()});
+        let _ = reply.try_send(Bytes::from(msg.to_string()));
+    } else if cmd.cmd == "project" {
+        let next = if cmd.fields.is_empty() {
+            None
+        } else {
+            let mut fields = cmd.fields;
+            fields.truncate(WS_MAX_PROJECTED_FIELDS);
+            Some(Arc::from(fields))
+        };
+        projection.send_replace(next);
+    }
+}
+
+/// The full state a client gets from `frame`: with profit tiers if it asked
+/// for them, gzip-compressed if it asked for that and the frame carries it.
+fn full_payload(frame: Frame, profit: bool, compress: bool) -> Bytes {
+    let (raw, gzip) = match frame.profit {
+        Some(with_profit) if profit => (with_profit, frame.profit_gzip),
+        _ => (frame.full, frame.full_gzip),
+    };
+    match gzip {
+        Some(gzip) if compress => gzip,
+        _ => raw,
+    }
+}
+
+/// `{"type":"snapshot","version":N,"state":{...}}` used by patch-mode clients.
+fn snapshot_frame(version: u64, full: &[u8]) -> Bytes {
+    let mut buf = Vec::with_capacity(full.len() + 48);
+    buf.extend_from_slice(b"{\"type\":\"snapshot\",\"version\":");
+    buf.extend_from_slice(itoa::Buffer::new().format(version).as_bytes());
+    buf.extend_from_slice(b",\"state\":");
+    buf.extend_from_slice(full);
+    buf.push(b'}');
+    Bytes::from(buf)
+}
+
+/// `{"candles":[...]}`: the newest `WS_CANDLE_REPLAY` candles, oldest first.
+fn candle_batch(state: &AppState, interval: u64) -> Bytes {
+    let candles = state.candles(interval);
+    let recent = &candles[candles.len().saturating_sub(*WS_CANDLE_REPLAY)..];
+    let body = serde_json::json!({ "interval": interval, "candles": recent });
+    Bytes::from(body.to_string())
+}
+
+/// `{"candle":{...}}`: the candle the newest tick landed in.
+fn candle_update(state: &AppState, interval: u64) -> Option<Bytes> {
+    let candle = state.latest_candle(interval)?;
+    let body = serde_json::json!({ "interval": interval, "candle": candle });
+    Some(Bytes::from(body.to_string()))
+}
+
+async fn handle_ws(mut socket: WebSocket, state: Arc<AppState>, opts: WsOptions, ip: String) {
+    let WsOptions { mode, profit, topics, compress, candles } = opts;
     let mut rx = match state.ws_manager.subscribe() {
         Some(rx) => rx,
-        None => return,
+        None => {
+            // 1013 "try again later" tells well-behaved clients to back off
+            debug!("WS rejected ip={}: at MAX_CONNECTIONS", ip);
+            let close = CloseFrame { code: 1013, reason: "Server penuh, coba lagi nanti".into() };
+            let _ = socket.send(Message::Close(Some(close))).await;
+            let _ = socket.close().await;
+            return;
+        }
     };
+    // Registered before the first send so no broadcast goes out without tiers
+    if profit {
+        state.ws_manager.add_profit_client();
+    }
+    if compress {
+        state.ws_manager.add_compress_client();
+    }
+    let usd_only = topics == Topics::USD && mode != WsMode::Patch;
+    if usd_only {
+        state.ws_manager.add_usd_client();
+    }
+    let connected_at = std::time::Instant::now();
+    info!("WS open ip={} mode={} channels={}", ip, mode.as_str(), topics.as_str());
 
     let (mut sender, mut receiver) = socket.split();
 
-    let initial = state.get_cached_state();
-    if sender
-        .send(Message::Binary(initial.to_vec().into()))
-        .await
-        .is_err()
-    {
+    let permit = state.ws_manager.handshake_permit().await;
+    let initial = if profit {
+        state.cached_profit_snapshot()
+    } else {
+        state.cached_snapshot()
+    };
+    let first = match mode {
+        _ if usd_only => state.usd_state(),
+        WsMode::Full | WsMode::Compact | WsMode::Append if compress => initial.gzip().clone(),
+        WsMode::Full | WsMode::Compact | WsMode::Append => initial.data.clone(),
+        WsMode::Patch => snapshot_frame(initial.version, &initial.data),
+    };
+    let mut sent = sender.send(Message::Binary(first.to_vec())).await.is_ok();
+    if let Some(interval) = candles.filter(|_| sent) {
+        let batch = candle_batch(&state, interval);
+        sent = sender.send(Message::Binary(batch.to_vec())).await.is_ok();
+    }
+    if !sent {
+        if profit {
+            state.ws_manager.remove_profit_client();
+        }
+        if compress {
+            state.ws_manager.remove_compress_client();
+        }
+        if usd_only {
+            state.ws_manager.remove_usd_client();
+        }
         state.ws_manager.unsubscribe();
         return;
     }
+    drop(permit);
 
-    let send_task = tokio::spawn(async move {
+    if mode == WsMode::Patch {
+        state.ws_manager.add_patch_client();
+    }
+    let mut version = initial.version;
+    let (projection_tx, projection_rx) = tokio::sync::watch::channel::<Projection>(None);
+    // Command replies from recv_task, written by send_task which owns the sink
+    let (reply_tx, mut reply_rx) = tokio::sync::mpsc::channel::<Bytes>(8);
+
+    let send_state = state.clone();
+    let mut send_task = tokio::spawn(async move {
+        let mut lags = LagWindow::default();
         loop {
-            match rx.recv().await {
-                Ok(data) => {
+            let received = tokio::select! {
+                Some(reply) = reply_rx.recv() => {
+                    let text = String::from_utf8_lossy(&reply).into_owned();
+                    if sender.send(Message::Text(text)).await.is_err() {
+                        return DisconnectReason::SendFailed;
+                    }
+                    continue;
+                }
+                received = rx.recv() => received,
+            };
+            match received {
+                // A patch client skipping frames gets a snapshot on the next one it takes
+                Ok(frame) if !frame.topics.intersects(topics) => continue,
+                Ok(frame) => {
+                    let gold_tick = frame.topics.intersects(Topics::GOLD);
+                    let data = match (mode, frame.version) {
+                        (_, Some(_)) if usd_only => frame.usd.unwrap_or_else(|| send_state.usd_state()),
+                        (WsMode::Patch, Some(v)) => {
+                            if v == version {
+                                continue;
+                            }
+                            // Patch only applies on top of the version this client holds
+                            let out = match frame.patch {
+                                Some((from, ref patch)) if from == version => patch.clone(),
+                                _ => snapshot_frame(v, &frame.full),
+                            };
+                            version = v;
+                            out
+                        }
+                        (WsMode::Full, Some(_)) => {
+                            let projection = projection_rx.borrow().clone();
+                            match projection {
+                                Some(fields) => {
+                                    let full = match frame.profit {
+                                        Some(with_profit) if profit => with_profit,
+                                        _ => frame.full,
+                                    };
+                                    project_state(&full, &fields).unwrap_or(full)
+                                }
+                                None => full_payload(frame, profit, compress),
+                            }
+                        }
+                        (WsMode::Append, Some(_)) => {
+                            let append = if profit { frame.append_profit.clone() } else { frame.append.clone() };
+                            match append {
+                                Some(append) => append,
+                                None => full_payload(frame, profit, compress),
+                            }
+                        }
+                        (WsMode::Compact, Some(_)) => match frame.compact {
+                            Some(compact) => compact,
+                            None => continue,
+                        },
+                        _ => frame.full,
+                    };
                     if sender
-                        .send(Message::Binary(data.to_vec().into()))
+                        .send(Message::Binary(data.to_vec()))
                         .await
                         .is_err()
                     {
-                        break;
+                        return DisconnectReason::SendFailed;
+                    }
+                    let candle = match candles {
+                        Some(interval) if gold_tick => {
+                            candle_update(&send_state, interval)
+                        }
+                        _ => None,
+                    };
+                    if let Some(candle) = candle {
+                        if sender.send(Message::Binary(candle.to_vec())).await.is_err() {
+                            return DisconnectReason::SendFailed;
+                        }
                     }
                 }
-                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
-                Err(_) => break,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    if lags.record() {
+                        return DisconnectReason::Lagged;
+                    }
+                    // Skipped frames can't be replayed; start over from a full snapshot
+                    let snap = if profit {
+                        send_state.cached_profit_snapshot()
+                    } else {
+                        send_state.cached_snapshot()
+                    };
+                    let projection = projection_rx.borrow().clone();
+                    let data = match mode {
+                        _ if usd_only => send_state.usd_state(),
+                        WsMode::Patch => snapshot_frame(snap.version, &snap.data),
+                        WsMode::Full if projection.is_some() => projection
+                            .and_then(|fields| project_state(&snap.data, &fields))
+                            .unwrap_or_else(|| snap.data.clone()),
+                        _ if compress => snap.gzip().clone(),
+                        _ => snap.data.clone(),
+                    };
+                    version = snap.version;
+                    debug!("WS resync after {} skipped frames", skipped);
+                    if sender.send(Message::Binary(data.to_vec())).await.is_err() {
+                        return DisconnectReason::SendFailed;
+                    }
+                }
+                Err(_) => return DisconnectReason::ServerShutdown,
             }
         }
     });
 
-    let recv_task = tokio::spawn(async move {
+    let recv_state = state.clone();
+    let mut recv_task = tokio::spawn(async move {
+        let mut limiter = CommandLimiter::default();
         loop {
-            match tokio::time::timeout(
+            let msg = match tokio::time::timeout(
                 tokio::time::Duration::from_secs(WS_TIMEOUT_SECS),
                 receiver.next(),
             )
             .await
             {
-                Ok(Some(Ok(Message::Text(_) | Message::Binary(_)))) => {}
-                _ => break,
+                Ok(Some(Ok(msg))) => msg,
+                Ok(Some(Err(_))) => return DisconnectReason::Protocol,
+                Ok(None) => return DisconnectReason::ClientClose,
+                Err(_) => return DisconnectReason::Timeout,
+            };
+            match msg {
+                Message::Text(text) => {
+                    handle_command(&text, &recv_state, &mut limiter, &projection_tx, &reply_tx)
+                }
+                Message::Binary(_) => {}
+                Message::Close(_) => return DisconnectReason::ClientClose,
+                _ => return DisconnectReason::Protocol,
             }
         }
     });
 
-    tokio::select! {
-        _ = send_task => {},
-        _ = recv_task => {},
+    let reason = tokio::select! {
+        r = &mut send_task => { recv_task.abort(); r },
+        r = &mut recv_task => { send_task.abort(); r },
     }
+    .unwrap_or(DisconnectReason::ServerShutdown);
+
+    info!(
+        "WS close ip={} mode={} duration={}s reason={}",
+        ip,
+        mode.as_str(),
+        connected_at.elapsed().as_secs(),
+        reason.as_str()
+    );
 
+    if mode == WsMode::Patch {
+        state.ws_manager.remove_patch_client();
+    }
+    if profit {
+        state.ws_manager.remove_profit_client();
+    }
+    if compress {
+        state.ws_manager.remove_compress_client();
+    }
+    if usd_only {
+        state.ws_manager.remove_usd_client();
+    }
     state.ws_manager.unsubscribe();
 }
 
+/// Admin allowlist and secret key check shared by the admin endpoints.
+/// Failures count towards the caller's block.
+fn check_admin(state: &AppState, ip: &str, key: Option<&str>) -> Result<(), ApiError> {
+    if !ADMIN_ALLOWLIST.is_empty() && !ip_in_list(ip, &ADMIN_ALLOWLIST) {
+        state.record_failed_attempt(ip, 3);
+        return Err(ApiError::forbidden());
+    }
+
+    let key = match key {
+        Some(k) if !k.is_empty() => k,
+        _ => {
+            state.record_failed_attempt(ip, 2);
+            return Err(ApiError::invalid_value("Parameter key diperlukan"));
+        }
+    };
+
+    if !verify_admin_key(key) {
+        state.record_failed_attempt(ip, 1);
+        return Err(ApiError::forbidden());
+    }
+    Ok(())
+}
+
+async fn security_ws_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LimitQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    let ip = client_ip(proxied, peer, &headers);
+    if state.is_ip_blocked(&ip) {
+        return ApiError::ip_blocked().into_response();
+    }
+    if let Err(rejection) = check_admin(&state, &ip, query.key.as_deref()) {
+        return rejection.into_response();
+    }
+    match ws {
+        Ok(ws) => ws.on_upgrade(move |socket| handle_security_ws(socket, state, ip)),
+        Err(_) => not_a_websocket(&headers),
+    }
+}
+
+/// Forwards `ip_blocked` / `ip_unblocked` events until the admin disconnects.
+async fn handle_security_ws(socket: WebSocket, state: Arc<AppState>, ip: String) {
+    let mut events = state.admin_events.subscribe();
+    let (mut sender, mut receiver) = socket.split();
+    info!("Security WS open ip={}", ip);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(data) => {
+                    if sender.send(Message::Text(String::from_utf8_lossy(&data).into_owned())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            },
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    info!("Security WS close ip={}", ip);
+}
+
 async fn set_limit(
     State(state): State<Arc<AppState>>,
     Path(value): Path<String>,
     Query(query): Query<LimitQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap,
 ) -> Response {
-    let ip = ip_from_headers(&headers);
+    let ip = &client_ip(proxied, peer, &headers);
 
     if state.is_ip_blocked(ip) {
-        return (StatusCode::TOO_MANY_REQUESTS, "IP diblokir sementara").into_response();
+        return ApiError::ip_blocked().into_response();
     }
 
-    let key = match query.key {
-        Some(ref k) if !k.is_empty() => k.as_str(),
-        _ => {
-            state.record_failed_attempt(ip, 2);
-            return (StatusCode::BAD_REQUEST, "Parameter key diperlukan").into_response();
-        }
-    };
-
-    let kb = key.as_bytes();
-    let sb = SECRET_KEY.as_bytes();
-    if kb.len() != sb.len() || kb.ct_eq(sb).unwrap_u8() != 1 {
-        state.record_failed_attempt(ip, 1);
-        return (StatusCode::FORBIDDEN, "Akses ditolak").into_response();
+    if let Err(rejection) = check_admin(&state, ip, query.key.as_deref()) {
+        return rejection.into_response();
     }
 
     let int_value: i64 = match value.parse() {
         Ok(v) => v,
         Err(_) => {
             state.record_failed_attempt(ip, 1);
-            return (StatusCode::BAD_REQUEST, "Nilai harus angka").into_response();
+            return ApiError::invalid_value("Nilai harus angka").into_response();
         }
     };
 
     let now = utils::current_timestamp();
     let last = state.last_successful_call.load(Ordering::Relaxed);
     if now - last < RATE_LIMIT_SECONDS {
-        return (StatusCode::TOO_MANY_REQUESTS, "Terlalu cepat").into_response();
+        return ApiError::rate_limited("Terlalu cepat").retry_after(RATE_LIMIT_SECONDS).into_response();
     }
 
-    if int_value < MIN_LIMIT || int_value > MAX_LIMIT {
-        return (
-            StatusCode::BAD_REQUEST,
-            format!("Nilai harus {}-{}", MIN_LIMIT, MAX_LIMIT),
-        )
-            .into_response();
+    if !(MIN_LIMIT..=MAX_LIMIT).contains(&int_value) {
+        return ApiError::invalid_value(format!("Nilai harus {}-{}", MIN_LIMIT, MAX_LIMIT)).into_response();
     }
 
     state.limit_bulan.store(int_value, Ordering::Relaxed);
     state.last_successful_call.store(now, Ordering::Relaxed);
-    state.invalidate_cache();
-
-    let cached = state.get_cached_state();
-    state.ws_manager.broadcast(cached);
+    state.publish_change(Topics::GOLD);
 
     (
         StatusCode::OK,
@@ -209,21 +1230,235 @@ async fn set_limit(
         .into_response()
 }
 
-async fn catch_all(State(state): State<Arc<AppState>>, headers: HeaderMap, uri: Uri) -> Response {
-    let ip = ip_from_headers(&headers);
+const MAX_PROFIT_TIERS: usize = 10;
+
+fn check_tiers(tiers: &[ProfitTier]) -> Result<(), String> {
+    if tiers.is_empty() || tiers.len() > MAX_PROFIT_TIERS {
+        return Err(format!("Jumlah tier harus 1-{}", MAX_PROFIT_TIERS));
+    }
+    let mut labels = std::collections::HashSet::new();
+    for t in tiers {
+        let label_ok = !t.label.is_empty()
+            && t.label.len() <= 32
+            && t.label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !label_ok {
+            return Err(format!("Label tidak valid: {}", t.label));
+        }
+        if !labels.insert(t.label.as_str()) {
+            return Err(format!("Label ganda: {}", t.label));
+        }
+        if t.modal <= 0 || t.pokok < 0 {
+            return Err(format!("Modal/pokok tidak valid: {}", t.label));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the profit tier list with a JSON array of
+/// `{"modal":..,"pokok":..,"label":".."}`.
+async fn set_tiers(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LimitQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let ip = &client_ip(proxied, peer, &headers);
+
+    if state.is_ip_blocked(ip) {
+        return ApiError::ip_blocked().into_response();
+    }
+
+    if let Err(rejection) = check_admin(&state, ip, query.key.as_deref()) {
+        return rejection.into_response();
+    }
+
+    let tiers: Vec<ProfitTier> = match serde_json::from_slice(&body) {
+        Ok(t) => t,
+        Err(_) => {
+            state.record_failed_attempt(ip, 1);
+            return ApiError::invalid_value("Body harus array JSON tier").into_response();
+        }
+    };
+    if let Err(msg) = check_tiers(&tiers) {
+        return ApiError::invalid_value(msg).into_response();
+    }
+
+    let now = utils::current_timestamp();
+    let last = state.last_successful_call.load(Ordering::Relaxed);
+    if now - last < RATE_LIMIT_SECONDS {
+        return ApiError::rate_limited("Terlalu cepat").retry_after(RATE_LIMIT_SECONDS).into_response();
+    }
+
+    let set = ProfitTierSet::new(tiers);
+    info!("Profit tiers replaced by {}: {} tiers (legacy={})", ip, set.tiers.len(), set.legacy);
+    state.profit_tiers.store(Arc::new(set));
+    state.last_successful_call.store(now, Ordering::Relaxed);
+    state.publish_change(Topics::GOLD);
+
+    let tiers = state.profit_tiers.load();
+    (
+        StatusCode::OK,
+        axum::Json(serde_json::json!({"status":"ok","tiers":tiers.tiers})),
+    )
+        .into_response()
+}
+
+/// Sends a tagged control frame through the WS broadcast channel and waits
+/// for it on an internal receiver. Clients ignore `{"type":"selftest"}`.
+async fn ws_selftest(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LimitQuery>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+    static SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    let ip = &client_ip(proxied, peer, &headers);
+    if state.is_ip_blocked(ip) {
+        return ApiError::ip_blocked().into_response();
+    }
+    if let Err(rejection) = check_admin(&state, ip, query.key.as_deref()) {
+        return rejection.into_response();
+    }
+
+    let id = SEQ.fetch_add(1, Ordering::Relaxed);
+    let probe = Bytes::from(format!("{{\"type\":\"selftest\",\"id\":{}}}", id));
+    let mut rx = state.ws_manager.probe();
+    let started = std::time::Instant::now();
+    state.ws_manager.broadcast(probe.clone());
+
+    let delivered = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match rx.recv().await {
+                Ok(frame) if frame.version.is_none() && frame.full == probe => return true,
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return false,
+            }
+        }
+    })
+    .await
+    .unwrap_or(false);
+
+    let status = if delivered { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (
+        status,
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        axum::Json(serde_json::json!({
+            "ok": delivered,
+            "latency_ms": delivered.then(|| started.elapsed().as_secs_f64() * 1000.0),
+            "ws_connections": state.ws_manager.count(),
+        })),
+    )
+        .into_response()
+}
+
+async fn catch_all(
+    State(state): State<Arc<AppState>>,
+    proxied: Option<Extension<ProxiedAddr>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let ip = &client_ip(proxied, peer, &headers);
     let path = uri.path().to_lowercase();
 
     if state.is_ip_blocked(ip) {
-        return (StatusCode::TOO_MANY_REQUESTS, "IP diblokir sementara").into_response();
+        return ApiError::ip_blocked().into_response();
     }
 
     if !path.starts_with("/aturt")
         && (path.contains("admin") || path.contains("config"))
     {
         state.record_failed_attempt(ip, 2);
-        return (StatusCode::FORBIDDEN, "Akses ditolak").into_response();
+        return ApiError::forbidden().into_response();
     }
 
     state.record_failed_attempt(ip, 1);
-    (StatusCode::NOT_FOUND, "Halaman tidak ditemukan").into_response()
+    ApiError::not_found("Halaman tidak ditemukan").into_response()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        routes().merge(ws_routes()).with_state(Arc::new(AppState::new()))
+    }
+
+    async fn get(uri: &str, headers: &[(&str, &str)]) -> Response {
+        let mut req = Request::get(uri);
+        for &(name, value) in headers {
+            req = req.header(name, value);
+        }
+        app().oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    fn header_of(resp: &Response, name: header::HeaderName) -> &str {
+        resp.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("")
+    }
+
+    #[tokio::test]
+    async fn read_endpoints_set_cache_headers() {
+        for uri in ["/api/state", "/api/ticker", "/api/stats", "/api/history"] {
+            let resp = get(uri, &[]).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(header_of(&resp, header::CACHE_CONTROL), "no-cache, must-revalidate", "{}", uri);
+            assert_eq!(header_of(&resp, header::VARY), "accept-encoding", "{}", uri);
+        }
+
+        let resp = get("/api/state", &[("accept-encoding", "gzip")]).await;
+        assert_eq!(header_of(&resp, header::CONTENT_ENCODING), "gzip");
+        assert_eq!(header_of(&resp, header::VARY), "accept-encoding");
+
+        let resp = get("/", &[]).await;
+        assert!(header_of(&resp, header::CACHE_CONTROL).contains("max-age=3600"));
+        assert_eq!(header_of(&resp, header::VARY), "accept-encoding");
+    }
+
+    #[tokio::test]
+    async fn unknown_ticker_field_gets_the_error_envelope() {
+        let resp = get("/api/ticker?fields=buy,nope", &[]).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["error"]["code"], "invalid_value");
+        assert_eq!(v["error"]["message"], "Field tidak dikenal: nope");
+    }
+
+    #[test]
+    fn candle_intervals_parse_within_bounds() {
+        assert_eq!(parse_candle_interval("1m"), Some(60));
+        assert_eq!(parse_candle_interval("15m"), Some(900));
+        assert_eq!(parse_candle_interval("4h"), Some(14_400));
+        assert_eq!(parse_candle_interval("1d"), Some(86_400));
+        assert_eq!(parse_candle_interval("300"), Some(300));
+        assert_eq!(parse_candle_interval("30"), None);
+        assert_eq!(parse_candle_interval("2d"), None);
+        assert_eq!(parse_candle_interval("m"), None);
+        assert_eq!(parse_candle_interval(""), None);
+    }
+
+    #[tokio::test]
+    async fn plain_get_to_ws_explains_the_endpoint() {
+        let resp = get("/ws", &[]).await;
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(header_of(&resp, header::UPGRADE), "websocket");
+        assert!(header_of(&resp, header::CONTENT_TYPE).starts_with("text/html"));
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("href=\"/\""));
+
+        let resp = get("/ws", &[("accept", "application/json")]).await;
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["error"]["code"], "websocket_required");
+    }
 }
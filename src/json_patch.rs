@@ -0,0 +1,132 @@
+use bytes::Bytes;
+use serde_json::{json, Map, Value};
+
+/// Longest front-shift of an array tried before falling back to an
+/// element-wise diff (a tick usually drops one entry off the front).
+const MAX_SHIFT: usize = 16;
+
+/// Computes RFC 6902 operations turning `a` into `b`.
+pub fn diff(a: &Value, b: &Value) -> Vec<Value> {
+    let mut ops = Vec::new();
+    let mut path = String::new();
+    diff_into(&mut path, a, b, &mut ops);
+    ops
+}
+
+fn push_token(path: &mut String, token: &str) {
+    path.push('/');
+    for c in token.chars() {
+        match c {
+            '~' => path.push_str("~0"),
+            '/' => path.push_str("~1"),
+            _ => path.push(c),
+        }
+    }
+}
+
+fn diff_into(path: &mut String, a: &Value, b: &Value, ops: &mut Vec<Value>) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => diff_objects(path, a, b, ops),
+        (Value::Array(a), Value::Array(b)) => diff_arrays(path, a, b, ops),
+        _ if a != b => ops.push(json!({"op": "replace", "path": path.as_str(), "value": b})),
+        _ => {}
+    }
+}
+
+fn diff_objects(path: &mut String, a: &Map<String, Value>, b: &Map<String, Value>, ops: &mut Vec<Value>) {
+    let base = path.len();
+    for key in a.keys().filter(|k| !b.contains_key(*k)) {
+        push_token(path, key);
+        ops.push(json!({"op": "remove", "path": path.as_str()}));
+        path.truncate(base);
+    }
+    for (key, bv) in b {
+        push_token(path, key);
+        match a.get(key) {
+            Some(av) => diff_into(path, av, bv, ops),
+            None => ops.push(json!({"op": "add", "path": path.as_str(), "value": bv})),
+        }
+        path.truncate(base);
+    }
+}
+
+fn diff_arrays(path: &mut String, a: &[Value], b: &[Value], ops: &mut Vec<Value>) {
+    let base = path.len();
+
+    // Sliding window: `b` is `a` with k items dropped from the front and some appended
+    let shift = (0..=a.len().min(MAX_SHIFT)).find(|&k| {
+        let kept = a.len() - k;
+        kept <= b.len() && (kept == 0 || a[k] == b[0]) && a[k..] == b[..kept]
+    });
+
+    if let Some(k) = shift {
+        path.push_str("/0");
+        for _ in 0..k {
+            ops.push(json!({"op": "remove", "path": path.as_str()}));
+        }
+        path.truncate(base);
+        path.push_str("/-");
+        for v in &b[a.len() - k..] {
+            ops.push(json!({"op": "add", "path": path.as_str(), "value": v}));
+        }
+        path.truncate(base);
+        return;
+    }
+
+    let common = a.len().min(b.len());
+    for i in 0..common {
+        push_token(path, itoa::Buffer::new().format(i));
+        diff_into(path, &a[i], &b[i], ops);
+        path.truncate(base);
+    }
+    for i in (common..a.len()).rev() {
+        push_token(path, itoa::Buffer::new().format(i));
+        ops.push(json!({"op": "remove", "path": path.as_str()}));
+        path.truncate(base);
+    }
+    path.push_str("/-");
+    for v in &b[common..] {
+        ops.push(json!({"op": "add", "path": path.as_str(), "value": v}));
+    }
+    path.truncate(base);
+}
+
+/// Remembers the last published state so the next one can be sent as a patch.
+#[derive(Default)]
+pub struct PatchTracker {
+    prev: Option<(u64, Value)>,
+}
+
+impl PatchTracker {
+    pub fn reset(&mut self) {
+        self.prev = None;
+    }
+
+    /// Records `data` as version `version` and returns a
+    /// `{"type":"patch","from":..,"to":..,"ops":[..]}` frame against the
+    /// previous version, or `None` when a full snapshot is the better choice.
+    pub fn advance(&mut self, version: u64, data: &Bytes) -> Option<(u64, Bytes)> {
+        if matches!(self.prev, Some((v, _)) if v >= version) {
+            return None;
+        }
+
+        let next: Value = serde_json::from_slice(data).ok()?;
+        let prev = self.prev.replace((version, next));
+        let (from, prev_value) = prev?;
+        let current = &self.prev.as_ref()?.1;
+
+        let frame = json!({
+            "type": "patch",
+            "from": from,
+            "to": version,
+            "ops": diff(&prev_value, current),
+        });
+        let bytes = serde_json::to_vec(&frame).ok()?;
+
+        // Not worth it when the patch is as big as the state itself
+        if bytes.len() >= data.len() {
+            return None;
+        }
+        Some((from, Bytes::from(bytes)))
+    }
+}
@@ -1,9 +1,16 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+mod alerts;
+mod api_error;
 mod config;
+mod follower;
 mod handlers;
+mod json_patch;
+mod persistence;
+mod proxy_protocol;
 mod rate_limiter;
+mod rng;
 mod security;
 mod state;
 mod template;
@@ -12,11 +19,15 @@ mod usd_idr;
 mod utils;
 mod ws_manager;
 
-use axum::{middleware as axum_middleware, Router};
+use axum::http::{Extensions, HeaderMap, StatusCode, Version};
+use axum::{error_handling::HandleErrorLayer, middleware as axum_middleware, Router};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::signal;
-use tower_http::compression::CompressionLayer;
-use tracing::info;
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
+use tower_http::compression::{predicate::DefaultPredicate, CompressionLayer, Predicate};
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
 use crate::state::AppState;
@@ -30,6 +41,21 @@ async fn main() {
         .compact()
         .init();
 
+    // Touching it validates the format at boot rather than on first build
+    if let Some(fmt) = config::WAKTU_FORMAT.as_deref() {
+        info!("waktu_display format {:?}", fmt);
+    }
+
+    if config::ADMIN_SECRET_HASH.is_none() {
+        warn!("ADMIN_SECRET is deprecated, configure ADMIN_SECRET_HASH (SHA-256 hex) instead");
+    }
+
+    let rl = &*config::RATE_LIMIT;
+    info!(
+        "Rate limit {} req/{}s (block at {} for {}s), failed-attempt block {}s",
+        rl.max_requests, rl.window_secs, rl.strict_max, rl.strict_block_secs, rl.block_secs
+    );
+
     let state = Arc::new(AppState::new());
 
     // Background tasks — spawn semua sekaligus
@@ -37,23 +63,68 @@ async fn main() {
     let s2 = state.clone();
     let s3 = state.clone();
 
-    tokio::spawn(async move { treasury::treasury_ws_loop(s1).await });
-    tokio::spawn(async move { usd_idr::usd_idr_loop(s2).await });
+    if *config::MAINTENANCE {
+        warn!("🚧 Maintenance mode: non-health routes answer 503");
+    }
+
+    if *config::MAINTENANCE && *config::MAINTENANCE_PAUSE_LOOPS {
+        info!("Feed loops paused for maintenance");
+    } else if let Some(leader) = config::LEADER_URL.as_deref() {
+        // Leader's state already carries USD/IDR, so no local polling either
+        info!("Follower mode, leader {}", leader);
+        tokio::spawn(async move { follower::follower_loop(s1).await });
+    } else {
+        if *config::INTEGRITY_CHECK_SECS > 0 {
+            let s = state.clone();
+            tokio::spawn(async move { treasury::integrity_loop(s).await });
+        }
+        tokio::spawn(async move { treasury::treasury_ws_loop(s1).await });
+        if *config::DISABLE_USD {
+            info!("USD/IDR polling disabled");
+        } else {
+            tokio::spawn(async move { usd_idr::usd_idr_loop(s2).await });
+        }
+    }
+    if *config::BROADCAST_CADENCE_MS > 0 {
+        let s = state.clone();
+        tokio::spawn(async move { ws_manager::cadence_loop(s).await });
+    }
     tokio::spawn(async move { ws_manager::heartbeat_loop(s3).await });
+    if config::DATA_FILE.is_some() && config::LEADER_URL.is_none() {
+        let s = state.clone();
+        tokio::spawn(async move { persistence::persistence_loop(s).await });
+    }
+    let persisted = state.clone();
 
-    // Compression: gzip + brotli + deflate
+    // Compression: gzip + brotli + deflate, except for responses that
+    // arrive already compressed (`/api/state`)
     let compression = CompressionLayer::new()
         .gzip(true)
         .br(true)
-        .deflate(true);
+        .deflate(true)
+        .compress_when(DefaultPredicate::new().and(
+            |_: StatusCode, _: Version, _: &HeaderMap, ext: &Extensions| {
+                ext.get::<handlers::Precompressed>().is_none()
+            },
+        ));
+
+    // Shared semaphore across all HTTP routes; excess requests are shed with 503
+    let http_routes = handlers::routes().layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handlers::overloaded))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(*config::MAX_INFLIGHT_REQUESTS)),
+    );
 
     let app = Router::new()
-        .merge(handlers::routes())
+        .merge(http_routes)
+        .merge(handlers::ws_routes())
         .layer(compression)
         .layer(axum_middleware::from_fn_with_state(
             state.clone(),
             security::security_middleware,
         ))
+        .layer(axum_middleware::from_fn(security::access_log_middleware))
         .with_state(state);
 
     let port: u16 = std::env::var("PORT")
@@ -61,16 +132,60 @@ async fn main() {
         .parse()
         .unwrap_or(10000);
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await
-        .unwrap();
+    let addr = bind_addr(port);
+    let listener = bind(addr).unwrap();
 
-    info!("⚡ Server ready on 0.0.0.0:{}", port);
+    info!("⚡ Server ready on {}", addr);
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    if *config::PROXY_PROTOCOL {
+        info!("PROXY protocol enabled on listener");
+        proxy_protocol::serve(listener, app, shutdown_signal()).await;
+    } else {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+    }
+
+    // Final save so changes since the last interval survive a deploy
+    if let (Some(path), None) = (config::DATA_FILE.as_deref(), config::LEADER_URL.as_deref()) {
+        persistence::save(&persisted, path);
+        info!("💾 History saved to {}", path);
+    }
+}
+
+/// `BIND_ADDR` as `ip:port`, or a bare IP combined with `PORT`.
+/// Defaults to `0.0.0.0:PORT`.
+fn bind_addr(port: u16) -> SocketAddr {
+    let raw = match std::env::var("BIND_ADDR") {
+        Ok(v) if !v.trim().is_empty() => v.trim().to_string(),
+        _ => return SocketAddr::from(([0, 0, 0, 0], port)),
+    };
+    if let Ok(addr) = raw.parse::<SocketAddr>() {
+        return addr;
+    }
+    match raw.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => {
+            warn!("BIND_ADDR {:?} is not an address, using 0.0.0.0:{}", raw, port);
+            SocketAddr::from(([0, 0, 0, 0], port))
+        }
+    }
+}
+
+/// Binds the listener. `[::]` is opened dual-stack so IPv4 clients still
+/// connect (as IPv4-mapped addresses).
+fn bind(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let domain = if addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
 }
 
 async fn shutdown_signal() {
@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::*;
+use crate::state::{AppState, GoldEntry, UsdIdrEntry};
+
+/// On-disk form of the state that should survive a restart.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub history: VecDeque<GoldEntry>,
+    #[serde(default)]
+    pub usd_idr_history: VecDeque<UsdIdrEntry>,
+    /// Buy rate the next tick's diff is computed against, if any.
+    #[serde(default)]
+    pub last_buy: Option<i64>,
+}
+
+/// Reads `path`; a missing file is silent, anything else unreadable is
+/// logged and treated as empty.
+pub fn load(path: &str) -> Option<Snapshot> {
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("⚠️ Could not read {}: {}, starting empty", path, e);
+            return None;
+        }
+    };
+    match serde_json::from_slice::<Snapshot>(&data) {
+        Ok(snap) => {
+            info!(
+                "💾 Loaded {} gold / {} USD entries from {}",
+                snap.history.len(),
+                snap.usd_idr_history.len(),
+                path
+            );
+            Some(snap)
+        }
+        Err(e) => {
+            warn!("⚠️ Could not parse {}: {}, starting empty", path, e);
+            None
+        }
+    }
+}
+
+fn snapshot(state: &AppState) -> Snapshot {
+    let has_last = state.has_last_buy.load(std::sync::atomic::Ordering::Relaxed);
+    Snapshot {
+        history: state.history.read().clone(),
+        usd_idr_history: state.usd_idr_history.read().clone(),
+        last_buy: has_last.then(|| state.last_buy.load(std::sync::atomic::Ordering::Relaxed)),
+    }
+}
+
+/// Writes to a temp file and renames it over `path`, so a crash mid-write
+/// never leaves a truncated snapshot behind.
+fn write_atomic(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+pub fn save(state: &AppState, path: &str) {
+    let data = match serde_json::to_vec(&snapshot(state)) {
+        Ok(d) => d,
+        Err(e) => {
+            warn!("⚠️ Snapshot serialization failed: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = write_atomic(path, &data) {
+        warn!("⚠️ Could not write {}: {}", path, e);
+    }
+}
+
+/// Saves every `PERSIST_INTERVAL_SECS`, skipping rounds where the cache
+/// version has not moved since the last write.
+pub async fn persistence_loop(state: Arc<AppState>) {
+    let path = match DATA_FILE.as_deref() {
+        Some(p) => p,
+        None => return,
+    };
+    let mut saved_version = state.cache_version();
+    loop {
+        tokio::time::sleep(Duration::from_secs(*PERSIST_INTERVAL_SECS)).await;
+        let version = state.cache_version();
+        if version == saved_version {
+            continue;
+        }
+        let s = state.clone();
+        let _ = tokio::task::spawn_blocking(move || save(&s, path)).await;
+        saved_version = version;
+    }
+}
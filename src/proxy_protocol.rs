@@ -0,0 +1,259 @@
+use axum::{extract::ConnectInfo, Router};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Real client address taken from a PROXY protocol header. Inserted into
+/// request extensions next to `ConnectInfo`, which holds the balancer's address.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxiedAddr(pub SocketAddr);
+
+/// Reads exactly one PROXY v1/v2 header from the stream. `Ok(None)` means the
+/// balancer sent a header without an address (v1 `UNKNOWN`, v2 `LOCAL`).
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, &'static str> {
+    let mut head = [0u8; 16];
+    stream.read_exact(&mut head[..5]).await.map_err(|_| "eof")?;
+
+    if &head[..5] == b"PROXY" {
+        return read_v1(stream).await;
+    }
+    if head[..5] != V2_SIGNATURE[..5] {
+        return Err("missing PROXY header");
+    }
+
+    stream.read_exact(&mut head[5..]).await.map_err(|_| "eof")?;
+    if head[..12] != V2_SIGNATURE {
+        return Err("bad v2 signature");
+    }
+    if head[12] >> 4 != 2 {
+        return Err("unsupported v2 version");
+    }
+
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await.map_err(|_| "eof")?;
+
+    // LOCAL command: health check from the balancer itself
+    if head[12] & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match head[13] >> 4 {
+        0x1 if len >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if len >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        0x1 | 0x2 => Err("truncated v2 address"),
+        _ => Ok(None),
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, &'static str> {
+    // Byte by byte so nothing past the header's CRLF is consumed; `serve`
+    // reads through a BufReader, so this is not a syscall per byte
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    line.extend_from_slice(b"PROXY");
+    loop {
+        let b = stream.read_u8().await.map_err(|_| "eof")?;
+        line.push(b);
+        if line.ends_with(b"\r\n") {
+            break;
+        }
+        if line.len() >= V1_MAX_LEN {
+            return Err("v1 header too long");
+        }
+    }
+
+    let text = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| "v1 not ascii")?;
+    let mut parts = text.split(' ');
+    parts.next();
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err("bad v1 protocol"),
+    }
+
+    let src: IpAddr = parts.next().and_then(|s| s.parse().ok()).ok_or("bad v1 source")?;
+    let _dst = parts.next().ok_or("bad v1 destination")?;
+    let port: u16 = parts.next().and_then(|s| s.parse().ok()).ok_or("bad v1 port")?;
+
+    Ok(Some(SocketAddr::new(src, port)))
+}
+
+/// Accept loop used instead of `axum::serve` when PROXY protocol is enabled.
+/// Connections without a valid header are dropped.
+pub async fn serve(listener: TcpListener, app: Router, shutdown: impl Future<Output = ()>) {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let app = app.clone();
+        let watcher = graceful.watcher();
+
+        tokio::spawn(async move {
+            // Kept for the whole connection, so bytes buffered past the header reach hyper
+            let mut stream = BufReader::new(stream);
+            let source = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                Ok(Ok(source)) => source,
+                Ok(Err(reason)) => {
+                    debug!("dropping {}: {}", peer, reason);
+                    return;
+                }
+                Err(_) => return,
+            };
+
+            let svc = app.map_request(move |mut req: axum::http::Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(peer));
+                if let Some(addr) = source {
+                    req.extensions_mut().insert(ProxiedAddr(addr));
+                }
+                req
+            });
+
+            let builder = Builder::new(TokioExecutor::new());
+            let conn = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(svc))
+                .into_owned();
+            let _ = watcher.watch(conn).await;
+        });
+    }
+
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, graceful.shutdown()).await;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `read_header` over `bytes`, returning the result and what is left.
+    async fn parse(bytes: &[u8]) -> (Result<Option<SocketAddr>, &'static str>, Vec<u8>) {
+        let mut rest = bytes;
+        let result = read_header(&mut rest).await;
+        (result, rest.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = V2_SIGNATURE.to_vec();
+        out.push(0x20 | command);
+        out.push(family << 4 | 0x1);
+        out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        s.parse().ok()
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_tcp6_and_unknown() {
+        let tcp4 = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        let (r, rest) = parse(tcp4).await;
+        assert_eq!(r, Ok(addr("203.0.113.7:51234")));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (r, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n").await;
+        assert_eq!(r, Ok(addr("[2001:db8::1]:51234")));
+
+        let (r, rest) = parse(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(r, Ok(None));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_without_crlf_is_cut_off() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(V1_MAX_LEN + 20, b'1');
+        let (r, rest) = parse(&line).await;
+        assert_eq!(r, Err("v1 header too long"));
+        assert_eq!(rest.len(), line.len() - V1_MAX_LEN, "stops reading at the limit");
+
+        assert_eq!(parse(b"PROXY TCP4 1.2.3.4").await.0, Err("eof"));
+        assert_eq!(parse(b"PROXY SCTP 1.2.3.4 5.6.7.8 1 2\r\n").await.0, Err("bad v1 protocol"));
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_over_ipv4_and_ipv6() {
+        let mut body = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        body.extend_from_slice(&51234u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        let mut stream = v2(0x1, 0x1, &body);
+        stream.extend_from_slice(b"GET");
+        let (r, rest) = parse(&stream).await;
+        assert_eq!(r, Ok(addr("203.0.113.7:51234")));
+        assert_eq!(rest, b"GET");
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut body = src.octets().to_vec();
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&51234u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        let (r, rest) = parse(&v2(0x1, 0x2, &body)).await;
+        assert_eq!(r, Ok(addr("[2001:db8::1]:51234")));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_local_has_no_address() {
+        let mut stream = v2(0x0, 0x0, &[]);
+        stream.extend_from_slice(b"GET");
+        let (r, rest) = parse(&stream).await;
+        assert_eq!(r, Ok(None));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_body_is_rejected() {
+        assert_eq!(parse(&v2(0x1, 0x1, &[203, 0, 113, 7])).await.0, Err("truncated v2 address"));
+
+        // Declared length longer than what arrives
+        let mut short = v2(0x1, 0x1, &[0; 12]);
+        short.truncate(short.len() - 4);
+        assert_eq!(parse(&short).await.0, Err("eof"));
+    }
+
+    #[tokio::test]
+    async fn bad_signatures_are_rejected() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n").await.0, Err("missing PROXY header"));
+        let mut bad = v2(0x1, 0x1, &[0; 12]);
+        bad[11] = b'X';
+        assert_eq!(parse(&bad).await.0, Err("bad v2 signature"));
+        let mut v3 = v2(0x1, 0x1, &[0; 12]);
+        v3[12] = 0x31;
+        assert_eq!(parse(&v3).await.0, Err("unsupported v2 version"));
+    }
+}
@@ -2,6 +2,7 @@ use dashmap::DashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::config::*;
+use crate::security::normalize_ip_key;
 use crate::utils;
 
 pub enum RateLimitStatus {
@@ -10,63 +11,150 @@ pub enum RateLimitStatus {
     Blocked,
 }
 
+/// Per-IP token bucket. Holds `RATE_LIMIT.max_requests` tokens, refilled
+/// evenly over `RATE_LIMIT.window_secs`; `capacity - tokens` approximates the
+/// requests allowed in the window. As with the old sliding window, rejected
+/// requests take no token, so `Blocked` only triggers when `strict_max`
+/// equals `max_requests`.
+struct Bucket {
+    tokens: f64,
+    /// Unix milliseconds of the last refill.
+    last_refill: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, now_ms: u64, capacity: f64, refill_per_ms: f64) {
+        let elapsed = now_ms.saturating_sub(self.last_refill) as f64;
+        self.tokens = (self.tokens + elapsed * refill_per_ms).min(capacity);
+        self.last_refill = now_ms;
+    }
+}
+
 pub struct RateLimiter {
-    requests: DashMap<String, Vec<u64>>,
+    buckets: DashMap<String, Bucket>,
     last_cleanup: AtomicU64,
+    capacity: f64,
+    refill_per_ms: f64,
+    max_requests: usize,
+    strict_max: usize,
 }
 
 impl RateLimiter {
     pub fn new() -> Self {
+        let cfg = &*RATE_LIMIT;
+        Self::with_limits(cfg.max_requests, cfg.strict_max, cfg.window_secs)
+    }
+
+    fn with_limits(max_requests: usize, strict_max: usize, window_secs: u64) -> Self {
+        let capacity = max_requests as f64;
         Self {
-            requests: DashMap::new(),
+            buckets: DashMap::new(),
             last_cleanup: AtomicU64::new(0),
+            capacity,
+            refill_per_ms: capacity / (window_secs as f64 * 1000.0),
+            max_requests,
+            strict_max,
         }
     }
 
-    fn cleanup(&self, now: u64) {
+    /// Drops buckets that have refilled completely; they hold no state.
+    fn cleanup(&self, now_ms: u64) {
         let last = self.last_cleanup.load(Ordering::Relaxed);
-        if now - last < 30 {
+        if now_ms.saturating_sub(last) < 30_000 {
             return;
         }
         if self
             .last_cleanup
-            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .compare_exchange(last, now_ms, Ordering::Relaxed, Ordering::Relaxed)
             .is_err()
         {
             return;
         }
 
-        let cutoff = now.saturating_sub(RATE_LIMIT_WINDOW);
-        let mut to_remove = Vec::new();
-        for mut entry in self.requests.iter_mut() {
-            entry.value_mut().retain(|&t| t > cutoff);
-            if entry.value().is_empty() {
-                to_remove.push(entry.key().clone());
-            }
-        }
-        for key in to_remove {
-            self.requests.remove(&key);
-        }
+        self.buckets.retain(|_, b| {
+            b.refill(now_ms, self.capacity, self.refill_per_ms);
+            b.tokens < self.capacity
+        });
     }
 
+    /// Counts a request from `ip`; IPv6 clients share a bucket per /64.
     pub fn check(&self, ip: &str) -> (bool, usize, RateLimitStatus) {
-        let now = utils::current_timestamp();
-        self.cleanup(now);
+        self.check_at(ip, utils::current_timestamp_ms())
+    }
 
-        let cutoff = now.saturating_sub(RATE_LIMIT_WINDOW);
-        let mut entry = self.requests.entry(ip.to_string()).or_default();
-        entry.retain(|&t| t > cutoff);
+    fn check_at(&self, ip: &str, now: u64) -> (bool, usize, RateLimitStatus) {
+        self.cleanup(now);
 
-        let count = entry.len();
+        let mut bucket = self.buckets.entry(normalize_ip_key(ip)).or_insert(Bucket {
+            tokens: self.capacity,
+            last_refill: now,
+        });
+        bucket.refill(now, self.capacity, self.refill_per_ms);
 
-        if count >= RATE_LIMIT_STRICT_MAX {
+        let count = (self.capacity - bucket.tokens).ceil().max(0.0) as usize;
+        if count >= self.strict_max {
             return (false, count, RateLimitStatus::Blocked);
         }
-        if count >= RATE_LIMIT_MAX_REQUESTS {
+        if count >= self.max_requests {
             return (false, count, RateLimitStatus::Limited);
         }
-
-        entry.push(now);
+        bucket.tokens -= 1.0;
         (true, count + 1, RateLimitStatus::Ok)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T0: u64 = 1_700_000_000_000;
+
+    fn status(r: (bool, usize, RateLimitStatus)) -> (bool, usize, &'static str) {
+        let name = match r.2 {
+            RateLimitStatus::Ok => "ok",
+            RateLimitStatus::Limited => "limited",
+            RateLimitStatus::Blocked => "blocked",
+        };
+        (r.0, r.1, name)
+    }
+
+    #[test]
+    fn allows_up_to_max_then_limits() {
+        let rl = RateLimiter::with_limits(5, 10, 60);
+        for n in 1..=5 {
+            assert_eq!(status(rl.check_at("1.2.3.4", T0)), (true, n, "ok"));
+        }
+        // Rejections take no token, so a client just over the limit stays limited
+        for _ in 0..20 {
+            assert_eq!(status(rl.check_at("1.2.3.4", T0)), (false, 5, "limited"));
+        }
+        assert_eq!(status(rl.check_at("5.6.7.8", T0)), (true, 1, "ok"), "buckets are per IP");
+    }
+
+    #[test]
+    fn blocks_at_strict_max() {
+        let rl = RateLimiter::with_limits(3, 3, 60);
+        for n in 1..=3 {
+            assert_eq!(status(rl.check_at("1.2.3.4", T0)), (true, n, "ok"));
+        }
+        assert_eq!(status(rl.check_at("1.2.3.4", T0)), (false, 3, "blocked"));
+    }
+
+    #[test]
+    fn tokens_refill_over_the_window() {
+        let rl = RateLimiter::with_limits(4, 8, 60);
+        for _ in 0..4 {
+            rl.check_at("1.2.3.4", T0);
+        }
+        assert_eq!(status(rl.check_at("1.2.3.4", T0)), (false, 4, "limited"));
+
+        // Half a window gives back half the tokens
+        let half = T0 + 30_000;
+        assert_eq!(status(rl.check_at("1.2.3.4", half)), (true, 3, "ok"));
+        assert_eq!(status(rl.check_at("1.2.3.4", half)), (true, 4, "ok"));
+        assert_eq!(status(rl.check_at("1.2.3.4", half)), (false, 4, "limited"));
+
+        let later = half + 60_000;
+        assert_eq!(status(rl.check_at("1.2.3.4", later)), (true, 1, "ok"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+use once_cell::sync::Lazy;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::RNG_SEED;
+
+const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// SplitMix64 over an atomic counter: lock-free, shareable, and reproducible
+/// when seeded. Not for anything security related.
+pub struct Rng {
+    state: AtomicU64,
+}
+
+impl Rng {
+    pub fn seeded(seed: u64) -> Self {
+        Self { state: AtomicU64::new(seed) }
+    }
+
+    fn from_entropy() -> Self {
+        // RandomState is keyed from OS randomness once per process
+        let mut h = RandomState::new().build_hasher();
+        h.write_u64(crate::utils::current_timestamp_ms());
+        Self::seeded(h.finish())
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut z = self.state.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..n` (0 when `n` is 0).
+    pub fn below(&self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+static RNG: Lazy<Rng> = Lazy::new(|| match *RNG_SEED {
+    Some(seed) => Rng::seeded(seed),
+    None => Rng::from_entropy(),
+});
+
+/// Process-wide generator, seeded from `RNG_SEED` when set. Today it only
+/// drives the reconnect jitter in `treasury`.
+pub fn rng() -> &'static Rng {
+    &RNG
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let (a, b) = (Rng::seeded(42), Rng::seeded(42));
+        let first: Vec<u64> = (0..16).map(|_| a.next_u64()).collect();
+        let second: Vec<u64> = (0..16).map(|_| b.next_u64()).collect();
+        assert_eq!(first, second);
+        let other = Rng::seeded(43);
+        assert_ne!(first, (0..16).map(|_| other.next_u64()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn below_stays_in_range() {
+        let r = Rng::seeded(42);
+        assert_eq!(r.below(0), 0);
+        assert!((0..1000).all(|_| r.below(7) < 7));
+    }
+}
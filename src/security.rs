@@ -1,32 +1,128 @@
 use axum::{
     body::Body,
-    extract::{Request, State},
-    http::{Response, StatusCode},
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, Response, StatusCode},
     middleware::Next,
     response::IntoResponse,
 };
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
 
+use crate::api_error::ApiError;
 use crate::config::*;
+use crate::proxy_protocol::ProxiedAddr;
 use crate::rate_limiter::RateLimitStatus;
 use crate::state::AppState;
+use crate::template::STATIC_PAGES;
+
+const HTML_503_MAINTENANCE: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Pemeliharaan</title></head><body><h1>Sedang dalam pemeliharaan</h1><p>Silakan coba lagi beberapa saat lagi.</p></body></html>";
 
 const HTML_429: &str = "<!DOCTYPE html><html><head><title>429</title></head><body><h1>Too Many Requests</h1></body></html>";
 
+/// Longest first hop read from forwarded headers; a bracketed IPv6 with a
+/// port fits well within this.
+const MAX_FORWARDED_HOP: usize = 64;
+
+/// First hop of `x-forwarded-for`, falling back to `x-real-ip`, if it is a
+/// real IP (optionally with a port). Only the leading `MAX_FORWARDED_HOP`
+/// bytes are inspected, so a huge chain costs no more than a short one; a
+/// hop that does not end within that window is ignored rather than truncated.
+pub fn forwarded_ip(h: &HeaderMap) -> Option<IpAddr> {
+    let first_hop = |name: &str| -> Option<IpAddr> {
+        let raw = h.get(name)?.as_bytes();
+        let window = &raw[..raw.len().min(MAX_FORWARDED_HOP + 1)];
+        let end = window.iter().position(|&b| b == b',').unwrap_or(window.len());
+        if end > MAX_FORWARDED_HOP {
+            return None;
+        }
+        let hop = std::str::from_utf8(&window[..end]).ok()?.trim();
+        hop.parse::<IpAddr>()
+            .ok()
+            .or_else(|| hop.parse::<SocketAddr>().ok().map(|s| s.ip()))
+            .map(|ip| ip.to_canonical())
+    };
+    first_hop("x-forwarded-for").or_else(|| first_hop("x-real-ip"))
+}
+
+/// Client IP used as the blocklist/rate-limit key: the PROXY protocol
+/// source, else the forwarded headers when `peer` is in `TRUSTED_PROXIES`,
+/// else `peer` itself. Without a peer the headers are not believed either.
+pub fn resolve_client_ip(proxied: Option<SocketAddr>, peer: Option<SocketAddr>, h: &HeaderMap) -> String {
+    if let Some(addr) = proxied {
+        return addr.ip().to_canonical().to_string();
+    }
+    let peer_ip = peer.map(|p| p.ip().to_canonical());
+    let trusted = peer_ip.is_some_and(|ip| TRUSTED_PROXIES.iter().any(|n| n.contains(&ip)));
+    let forwarded = if trusted { forwarded_ip(h) } else { None };
+    match forwarded.or(peer_ip) {
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Key the rate limiter and blocklist track `ip` under: IPv6 addresses
+/// collapse to their /64 (`2001:db8:1:2::/64`), since one subscriber usually
+/// holds the whole prefix. IPv4, IPv4-mapped IPv6 and unparsable values
+/// are kept as they are, apart from unmapping.
+pub fn normalize_ip_key(ip: &str) -> String {
+    match ip.parse::<IpAddr>().map(|a| a.to_canonical()) {
+        Ok(IpAddr::V6(v6)) => {
+            let prefix = u128::from(v6) & (u128::MAX << 64);
+            format!("{}/64", std::net::Ipv6Addr::from(prefix))
+        }
+        Ok(IpAddr::V4(v4)) => v4.to_string(),
+        Err(_) => ip.to_string(),
+    }
+}
+
 pub fn get_client_ip(req: &Request) -> String {
-    if let Some(v) = req.headers().get("x-forwarded-for") {
-        if let Ok(s) = v.to_str() {
-            if let Some(first) = s.split(',').next() {
-                return first.trim().to_string();
-            }
+    let ext = req.extensions();
+    resolve_client_ip(
+        ext.get::<ProxiedAddr>().map(|p| p.0),
+        ext.get::<ConnectInfo<SocketAddr>>().map(|c| c.0),
+        req.headers(),
+    )
+}
+
+/// True when `ip` parses and falls inside one of `nets`.
+pub fn ip_in_list(ip: &str, nets: &[ipnet::IpNet]) -> bool {
+    match ip.parse::<std::net::IpAddr>() {
+        Ok(addr) => nets.iter().any(|n| n.contains(&addr)),
+        Err(_) => false,
+    }
+}
+
+fn has_monitor_token(req: &Request) -> bool {
+    let expected = match MONITOR_TOKEN.as_deref() {
+        Some(t) => t.as_bytes(),
+        None => return false,
+    };
+    match req.headers().get("x-monitor-token") {
+        Some(v) => {
+            let got = v.as_bytes();
+            got.len() == expected.len() && got.ct_eq(expected).unwrap_u8() == 1
         }
+        None => false,
     }
-    if let Some(v) = req.headers().get("x-real-ip") {
-        if let Ok(s) = v.to_str() {
-            return s.trim().to_string();
+}
+
+/// Checks an admin key against `ADMIN_SECRET_HASH`, or against the
+/// deprecated plaintext `ADMIN_SECRET` when no hash is configured. Both
+/// comparisons are constant-time.
+pub fn verify_admin_key(key: &str) -> bool {
+    match ADMIN_SECRET_HASH.as_ref() {
+        Some(expected) => {
+            let got = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+            got.as_ref().ct_eq(expected).unwrap_u8() == 1
+        }
+        None => {
+            let (kb, sb) = (key.as_bytes(), SECRET_KEY.as_bytes());
+            kb.len() == sb.len() && kb.ct_eq(sb).unwrap_u8() == 1
         }
     }
-    "unknown".to_string()
 }
 
 fn is_suspicious(path: &str) -> bool {
@@ -37,7 +133,51 @@ fn is_suspicious(path: &str) -> bool {
     SUSPICIOUS_PATHS.iter().any(|&s| p.contains(s))
 }
 
-fn response_429() -> Response<Body> {
+/// Path prefixes where a missing User-Agent counts as a scanner. Elsewhere
+/// bare clients (curl scripts, uptime checks) are common and harmless.
+const EMPTY_AGENT_PATHS: &[&str] = &["/api/export", "/ws/security"];
+
+/// User-Agent matching the scanner deny list, or missing/empty on one of
+/// `EMPTY_AGENT_PATHS`.
+fn is_suspicious_agent(path: &str, ua: Option<&str>) -> bool {
+    let ua = match ua.map(str::trim) {
+        Some(ua) if !ua.is_empty() => ua.to_lowercase(),
+        _ => return EMPTY_AGENT_PATHS.iter().any(|p| path.starts_with(p)),
+    };
+    SUSPICIOUS_USER_AGENTS.iter().any(|s| ua.contains(s.as_str()))
+}
+
+/// Paths exempt from the generic rate limiter.
+fn is_whitelisted(path: &str, pl: &str) -> bool {
+    matches!(
+        path,
+        "/" | "/ws"
+            | "/sse"
+            | "/health"
+            | "/health/live"
+            | "/health/deep"
+            | "/api/state"
+            | "/api/stats"
+            | "/api/ticker"
+            | "/api/connections"
+            | "/api/schema"
+            | "/metrics.json"
+    ) || pl.starts_with("/aturt")
+        || STATIC_PAGES.iter().any(|&(p, _)| p == path)
+}
+
+/// API clients (`Accept: application/json` without `text/html`) get JSON.
+fn wants_json(req: &Request) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|a| a.contains("application/json") && !a.contains("text/html"))
+}
+
+fn response_429(json: bool) -> Response<Body> {
+    if json {
+        return ApiError::rate_limited("Terlalu banyak permintaan").retry_after(60).into_response();
+    }
     Response::builder()
         .status(StatusCode::TOO_MANY_REQUESTS)
         .header("Content-Type", "text/html")
@@ -46,41 +186,245 @@ fn response_429() -> Response<Body> {
         .unwrap()
 }
 
+fn response_maintenance(json: bool) -> Response<Body> {
+    let mut resp = if json {
+        ApiError::unavailable("maintenance", "Sedang dalam pemeliharaan")
+            .retry_after(300)
+            .into_response()
+    } else {
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Retry-After", "300")
+            .body(Body::from(HTML_503_MAINTENANCE))
+            .unwrap()
+    };
+    resp.headers_mut()
+        .insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+    resp
+}
+
+/// The maintenance 503 for `req` when `maintenance` is on, except on
+/// `/health` and `/health/live`. `/health/deep` reports maintenance like any
+/// other route so readiness fails.
+fn maintenance_gate(maintenance: bool, req: &Request) -> Option<Response<Body>> {
+    let path = req.uri().path();
+    if !maintenance || path == "/health" || path == "/health/live" {
+        return None;
+    }
+    Some(response_maintenance(wants_json(req)))
+}
+
+/// Logs method, path, client IP, status and duration of every request
+/// except health checks, under the `access` target. Runs outside
+/// `security_middleware` so rejected requests show up too.
+pub async fn access_log_middleware(req: Request, next: Next) -> Response<Body> {
+    let path = req.uri().path().to_string();
+    if !*ACCESS_LOG || path.starts_with("/health") {
+        return next.run(req).await;
+    }
+
+    let method = req.method().clone();
+    let ip = get_client_ip(&req);
+    let started = Instant::now();
+    let resp = next.run(req).await;
+
+    info!(
+        target: "access",
+        method = %method,
+        path = %path,
+        ip = %ip,
+        status = resp.status().as_u16(),
+        ms = started.elapsed().as_secs_f64() * 1000.0,
+        "request"
+    );
+    resp
+}
+
 pub async fn security_middleware(
     State(state): State<Arc<AppState>>,
     req: Request,
     next: Next,
 ) -> impl IntoResponse {
+    // Before everything else so liveness probes keep passing
+    if let Some(resp) = maintenance_gate(*MAINTENANCE, &req) {
+        return resp;
+    }
+
+    if has_monitor_token(&req) {
+        return next.run(req).await.into_response();
+    }
+
     let ip = get_client_ip(&req);
     let path = req.uri().path().to_string();
     let pl = path.to_lowercase();
+    let json = wants_json(&req);
 
     if state.is_ip_blocked(&ip) {
-        return response_429();
+        if *TARPIT_MS > 0 {
+            if let Ok(_slot) = state.tarpit_slots.try_acquire() {
+                tokio::time::sleep(std::time::Duration::from_millis(*TARPIT_MS)).await;
+            }
+        }
+        return response_429(json);
     }
 
-    let whitelisted =
-        path == "/ws" || path == "/api/state" || path == "/health" || path == "/" || pl.starts_with("/aturt");
-
+    let whitelisted = is_whitelisted(&path, &pl);
     if !whitelisted {
         let (_ok, _count, status) = state.rate_limiter.check(&ip);
         match status {
             RateLimitStatus::Blocked => {
-                state.block_ip(&ip, 600);
-                return response_429();
+                state.block_ip(&ip, RATE_LIMIT.strict_block_secs);
+                return response_429(json);
             }
-            RateLimitStatus::Limited => return response_429(),
+            RateLimitStatus::Limited => return response_429(json),
             RateLimitStatus::Ok => {}
         }
     }
 
-    if is_suspicious(&path) {
+    // Whitelisted paths are exempt so health checkers with bare agents keep working
+    let ua = req.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok());
+    if is_suspicious(&path) || (!whitelisted && is_suspicious_agent(&pl, ua)) {
         state.record_failed_attempt(&ip, 3);
-        return Response::builder()
-            .status(StatusCode::FORBIDDEN)
-            .body(Body::from(r#"{"error":"forbidden"}"#))
-            .unwrap();
+        return ApiError::forbidden().into_response();
+    }
+
+    let started = Instant::now();
+    let resp = next.run(req).await.into_response();
+
+    if *SLOW_REQUEST_MS > 0 && path != "/ws" {
+        let elapsed = started.elapsed().as_millis();
+        if elapsed >= *SLOW_REQUEST_MS as u128 {
+            warn!("🐢 Slow request {} from {} took {}ms", path, ip, elapsed);
+        }
+    }
+
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_headers_only_count_from_trusted_peers() {
+        let mut h = HeaderMap::new();
+        h.insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        let peer = |s: &str| s.parse::<SocketAddr>().ok();
+
+        assert_eq!(resolve_client_ip(None, peer("127.0.0.1:9000"), &h), "203.0.113.7");
+        assert_eq!(resolve_client_ip(None, peer("[::1]:9000"), &h), "203.0.113.7");
+        assert_eq!(resolve_client_ip(None, peer("10.0.0.5:9000"), &h), "10.0.0.5");
+        assert_eq!(resolve_client_ip(None, peer("198.51.100.2:9000"), &h), "198.51.100.2");
+        assert_eq!(resolve_client_ip(None, None, &h), "unknown");
+    }
+
+    #[test]
+    fn ipv6_keys_collapse_to_their_64() {
+        assert_eq!(normalize_ip_key("::1"), "::/64");
+        let full = normalize_ip_key("2001:0db8:0001:0002:0000:0000:0000:0001");
+        assert_eq!(full, "2001:db8:1:2::/64");
+        assert_eq!(normalize_ip_key("2001:db8:1:2:ffff::9"), full);
+        assert_ne!(normalize_ip_key("2001:db8:1:3::1"), full);
+    }
+
+    #[test]
+    fn ipv4_and_unparsable_keys_pass_through() {
+        assert_eq!(normalize_ip_key("::ffff:1.2.3.4"), "1.2.3.4");
+        assert_eq!(normalize_ip_key("1.2.3.4"), "1.2.3.4");
+        assert_eq!(normalize_ip_key("unknown"), "unknown");
+        assert_eq!(normalize_ip_key("2001:db8::zz"), "2001:db8::zz");
+    }
+
+    fn request(path: &str) -> Request {
+        Request::builder().uri(path).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn maintenance_exempts_only_liveness_checks() {
+        for path in ["/health", "/health/live"] {
+            assert!(maintenance_gate(true, &request(path)).is_none(), "{path}");
+        }
+        for path in ["/", "/api/state", "/health/deep", "/ws", "/healthz"] {
+            let resp = maintenance_gate(true, &request(path)).expect(path);
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{path}");
+        }
+        assert!(maintenance_gate(false, &request("/api/state")).is_none());
+    }
+
+    #[tokio::test]
+    async fn maintenance_json_body_and_retry_after() {
+        let resp = response_maintenance(true);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "300");
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-store");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["error"]["code"], "maintenance");
+    }
+
+    #[tokio::test]
+    async fn maintenance_html_body_and_retry_after() {
+        let resp = response_maintenance(false);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "300");
+        assert!(resp.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, HTML_503_MAINTENANCE.as_bytes());
+    }
+
+    fn forwarded(xff: &str) -> Option<IpAddr> {
+        let mut h = HeaderMap::new();
+        h.insert("x-forwarded-for", xff.parse().unwrap());
+        forwarded_ip(&h)
+    }
+
+    #[test]
+    fn forwarded_ip_takes_the_first_hop_of_a_huge_chain() {
+        let chain = format!("203.0.113.7, {}", "10.0.0.1, ".repeat(10_000));
+        assert_eq!(forwarded(&chain), "203.0.113.7".parse().ok());
     }
 
-    next.run(req).await.into_response()
+    #[test]
+    fn forwarded_ip_ignores_an_oversized_first_hop() {
+        let hop = format!("{}203.0.113.7", " ".repeat(MAX_FORWARDED_HOP));
+        assert_eq!(forwarded(&hop), None);
+        assert_eq!(forwarded(&format!("{}, 10.0.0.1", hop)), None);
+
+        let mut h = HeaderMap::new();
+        h.insert("x-forwarded-for", hop.parse().unwrap());
+        h.insert("x-real-ip", "198.51.100.2".parse().unwrap());
+        assert_eq!(forwarded_ip(&h), "198.51.100.2".parse().ok());
+    }
+
+    #[test]
+    fn forwarded_ip_accepts_ports_and_brackets() {
+        assert_eq!(forwarded("[2001:db8::1]:443"), "2001:db8::1".parse().ok());
+        assert_eq!(forwarded("203.0.113.7:8080, 10.0.0.1"), "203.0.113.7".parse().ok());
+        assert_eq!(forwarded("::ffff:203.0.113.7"), "203.0.113.7".parse().ok());
+    }
+
+    #[test]
+    fn forwarded_ip_rejects_garbage() {
+        for junk in ["", "unknown", "not-an-ip, 10.0.0.1", "999.1.1.1", "[2001:db8::1", "1.2.3.4:port"] {
+            assert_eq!(forwarded(junk), None, "{junk:?}");
+        }
+    }
+
+    #[test]
+    fn known_scanner_agents_are_suspicious() {
+        assert!(is_suspicious_agent("/api/history", Some("sqlmap/1.7.2#stable (https://sqlmap.org)")));
+        assert!(is_suspicious_agent("/api/history", Some("Mozilla/5.00 (Nikto/2.5.0)")));
+        assert!(is_suspicious_agent("/api/history", Some("Fuzz Faster U Fool v2.1.0 (ffuf)")));
+        assert!(!is_suspicious_agent("/api/history", Some("Mozilla/5.0 (X11; Linux x86_64) Firefox/128.0")));
+        assert!(!is_suspicious_agent("/api/history", Some("FuzzyClock/1.0")));
+    }
+
+    #[test]
+    fn empty_agent_only_counts_on_listed_paths() {
+        assert!(!is_suspicious_agent("/api/history", None));
+        assert!(!is_suspicious_agent("/api/candles", Some("  ")));
+        assert!(is_suspicious_agent("/api/export.csv", None));
+        assert!(is_suspicious_agent("/ws/security", Some("")));
+    }
 }
\ No newline at end of file
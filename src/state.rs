@@ -1,7 +1,8 @@
 use arc_swap::ArcSwap;
 use bytes::Bytes;
 use dashmap::DashMap;
-use parking_lot::{Mutex, RwLock};
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use std::collections::{HashSet, VecDeque};
 use std::io::Write;
 use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
@@ -9,8 +10,10 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use crate::config::*;
+use crate::json_patch::PatchTracker;
+use crate::security::normalize_ip_key;
 use crate::utils;
-use crate::ws_manager::WsManager;
+use crate::ws_manager::{Frame, Topics, WsManager};
 
 // ─── Data Structures ───
 
@@ -21,32 +24,37 @@ pub struct GoldEntry {
     pub status: String,
     pub diff: i64,
     pub created_at: String,
+    /// Provenance: `live`, `backfill`, `injected`, `imported`, or a channel name.
+    #[serde(default = "default_source")]
+    pub source: String,
+    /// Unrecognised Treasury fields, kept only with `PASSTHROUGH_EXTRA_FIELDS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+pub const SOURCE_LIVE: &str = "live";
+/// Ticks recovered from `TREASURY_HISTORY_URL` after a reconnect.
+pub const SOURCE_BACKFILL: &str = "backfill";
+
+fn default_source() -> String {
+    SOURCE_LIVE.into()
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct UsdIdrEntry {
     pub price: String,
+    /// `YYYY-MM-DD HH:MM:SS` WIB; bare `HH:MM:SS` in old snapshots.
     pub time: String,
-}
-
-#[derive(serde::Serialize)]
-struct HistoryItem<'a> {
-    buying_rate: &'a str,
-    selling_rate: &'a str,
-    buying_rate_raw: i64,
-    selling_rate_raw: i64,
-    waktu_display: &'a str,
-    diff_display: &'a str,
-    transaction_display: &'a str,
-    created_at: &'a str,
-    jt10: &'a str,
-    jt20: &'a str,
-    jt30: &'a str,
-    jt40: &'a str,
-    jt50: &'a str,
+    /// `HH:MM:SS` for the UI. Empty in old snapshots.
+    #[serde(default)]
+    pub time_display: String,
+    /// Source that produced the price, e.g. `google`. Empty in old snapshots.
+    #[serde(default)]
+    pub source: String,
 }
 
 // Owned version for building
+#[derive(serde::Serialize)]
 struct HistoryItemOwned {
     buying_rate: String,
     selling_rate: String,
@@ -56,31 +64,369 @@ struct HistoryItemOwned {
     diff_display: String,
     transaction_display: String,
     created_at: String,
-    jt10: String,
-    jt20: String,
-    jt30: String,
-    jt40: String,
-    jt50: String,
+    source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(flatten)]
+    profit: Option<ProfitValues>,
+}
+
+/// Capital tier for the estimated profit columns.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProfitTier {
+    pub modal: i64,
+    pub pokok: i64,
+    pub label: String,
+}
+
+/// Active tier list. `legacy` marks the stock list, which keeps its flat
+/// `jt10`..`jt50` item fields; any other list is emitted as a `tiers` object.
+pub struct ProfitTierSet {
+    pub tiers: Vec<ProfitTier>,
+    pub legacy: bool,
+}
+
+impl ProfitTierSet {
+    pub fn new(tiers: Vec<ProfitTier>) -> Self {
+        let legacy = tiers == default_profit_tiers();
+        Self { tiers, legacy }
+    }
+}
+
+pub fn default_profit_tiers() -> Vec<ProfitTier> {
+    [
+        (10_000_000, 9_669_000),
+        (20_000_000, 19_330_000),
+        (30_000_000, 28_995_000),
+        (40_000_000, 38_660_000),
+        (50_000_000, 48_325_000),
+    ]
+    .into_iter()
+    .map(|(modal, pokok)| ProfitTier { modal, pokok, label: format!("jt{}", modal / 1_000_000) })
+    .collect()
+}
+
+/// Estimated profit per capital tier, only built when a client asks for it.
+/// `values` follows the order of `set.tiers`.
+struct ProfitValues {
+    set: Arc<ProfitTierSet>,
+    values: Vec<String>,
+}
+
+impl ProfitValues {
+    fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.set.tiers.iter().map(|t| t.label.as_str()).zip(self.values.iter().map(String::as_str))
+    }
 }
 
-impl HistoryItemOwned {
-    fn as_ref(&self) -> HistoryItem<'_> {
-        HistoryItem {
-            buying_rate: &self.buying_rate,
-            selling_rate: &self.selling_rate,
-            buying_rate_raw: self.buying_rate_raw,
-            selling_rate_raw: self.selling_rate_raw,
-            waktu_display: &self.waktu_display,
-            diff_display: &self.diff_display,
-            transaction_display: &self.transaction_display,
-            created_at: &self.created_at,
-            jt10: &self.jt10,
-            jt20: &self.jt20,
-            jt30: &self.jt30,
-            jt40: &self.jt40,
-            jt50: &self.jt50,
+impl serde::Serialize for ProfitValues {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        struct Tiers<'a>(&'a ProfitValues);
+        impl serde::Serialize for Tiers<'_> {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.collect_map(self.0.entries())
+            }
+        }
+
+        if self.set.legacy {
+            Tiers(self).serialize(s)
+        } else {
+            let mut m = s.serialize_map(Some(1))?;
+            m.serialize_entry("tiers", &Tiers(self))?;
+            m.end()
+        }
+    }
+}
+
+/// Time/size/source filter shared by the history endpoints. Bounds are unix
+/// seconds, inclusive; `source` keeps only entries tagged with it (`live`, ...).
+#[derive(Default, serde::Deserialize)]
+pub struct HistoryFilter {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+    pub source: Option<String>,
+}
+
+impl HistoryFilter {
+    pub fn is_valid(&self) -> bool {
+        !matches!((self.from, self.to), (Some(f), Some(t)) if f > t)
+    }
+
+    fn matches(&self, e: &GoldEntry) -> bool {
+        if self.source.as_deref().is_some_and(|s| s != e.source) {
+            return false;
+        }
+        if self.from.is_none() && self.to.is_none() {
+            return true;
+        }
+        match utils::parse_created_at(&e.created_at) {
+            Some(ts) => self.from.is_none_or(|f| ts >= f) && self.to.is_none_or(|t| ts <= t),
+            None => false,
+        }
+    }
+}
+
+/// The most recent `SHOWN_UPDATES_CAP` tick keys, oldest evicted first.
+#[derive(Default)]
+pub struct DedupSet {
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+    pub evictions: u64,
+}
+
+impl DedupSet {
+    /// Records `key`; false if it is already among the retained keys.
+    pub fn insert(&mut self, key: String) -> bool {
+        if self.seen.contains(&key) {
+            return false;
+        }
+        while self.order.len() >= *SHOWN_UPDATES_CAP {
+            match self.order.pop_front() {
+                Some(old) => {
+                    self.seen.remove(&old);
+                    self.evictions += 1;
+                }
+                None => break,
+            }
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+}
+
+/// Delay between a tick's `created_at` and the moment we processed it.
+#[derive(Clone, Copy, Default, serde::Serialize)]
+pub struct FeedLag {
+    pub samples: u64,
+    pub last_ms: u64,
+    /// Exponential moving average (alpha 0.1).
+    pub avg_ms: f64,
+    pub max_ms: u64,
+    /// Ticks stamped in our future (clock skew), counted as zero lag.
+    pub skewed: u64,
+}
+
+impl FeedLag {
+    pub fn record(&mut self, created_ts: u64, now_ms: u64) {
+        let created_ms = created_ts.saturating_mul(1000);
+        let lag = if now_ms >= created_ms {
+            now_ms - created_ms
+        } else {
+            self.skewed += 1;
+            0
+        };
+
+        self.avg_ms = if self.samples == 0 {
+            lag as f64
+        } else {
+            self.avg_ms * 0.9 + lag as f64 * 0.1
+        };
+        self.samples += 1;
+        self.last_ms = lag;
+        self.max_ms = self.max_ms.max(lag);
+    }
+}
+
+/// Timing of USD/IDR page fetches, for tuning `USD_TIMEOUTS`.
+#[derive(Clone, Copy, Default, serde::Serialize)]
+pub struct FetchStats {
+    pub fetches: u64,
+    pub last_ms: u64,
+    /// Exponential moving average (alpha 0.1).
+    pub avg_ms: f64,
+    pub timeouts: u64,
+    pub failures: u64,
+}
+
+impl FetchStats {
+    pub fn record(&mut self, elapsed_ms: u64, ok: bool, timed_out: bool) {
+        self.avg_ms = if self.fetches == 0 {
+            elapsed_ms as f64
+        } else {
+            self.avg_ms * 0.9 + elapsed_ms as f64 * 0.1
+        };
+        self.fetches += 1;
+        self.last_ms = elapsed_ms;
+        if timed_out {
+            self.timeouts += 1;
+        } else if !ok {
+            self.failures += 1;
+        }
+    }
+}
+
+/// Per-tick encodings riding along with a state broadcast.
+struct TickFrames {
+    compact: Bytes,
+    append: Option<Bytes>,
+    append_profit: Option<Bytes>,
+}
+
+/// Time writers spent waiting for the `history` write lock.
+#[derive(Clone, Copy, Default, serde::Serialize)]
+pub struct LockWaitStats {
+    pub acquisitions: u64,
+    /// Acquisitions that found the lock held and had to wait.
+    pub contended: u64,
+    pub last_us: u64,
+    /// Exponential moving average over contended waits (alpha 0.1).
+    pub avg_us: f64,
+    pub max_us: u64,
+}
+
+impl LockWaitStats {
+    fn record(&mut self, waited_us: Option<u64>) {
+        self.acquisitions += 1;
+        let Some(us) = waited_us else { return };
+        self.avg_us = if self.contended == 0 { us as f64 } else { self.avg_us * 0.9 + us as f64 * 0.1 };
+        self.contended += 1;
+        self.last_us = us;
+        self.max_us = self.max_us.max(us);
+    }
+}
+
+/// Field selectable in `/api/ticker?fields=`. Output follows declaration order.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TickerField {
+    Buy,
+    Sell,
+    Diff,
+    Status,
+    Usd,
+    Ts,
+}
+
+impl TickerField {
+    const ALL: [TickerField; 6] = [
+        TickerField::Buy,
+        TickerField::Sell,
+        TickerField::Diff,
+        TickerField::Status,
+        TickerField::Usd,
+        TickerField::Ts,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TickerField::Buy => "buy",
+            TickerField::Sell => "sell",
+            TickerField::Diff => "diff",
+            TickerField::Status => "status",
+            TickerField::Usd => "usd",
+            TickerField::Ts => "ts",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Set of ticker fields as a bitmask; doubles as the ticker cache key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TickerFields(u8);
+
+impl TickerFields {
+    pub const ALL: TickerFields = TickerFields(0b11_1111);
+
+    /// Parses a comma-separated list. Unknown names are returned as the error.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut mask = 0u8;
+        for name in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match TickerField::ALL.iter().find(|f| f.name() == name) {
+                Some(f) => mask |= f.bit(),
+                None => return Err(name.to_string()),
+            }
+        }
+        if mask == 0 {
+            return Ok(Self::ALL);
         }
+        Ok(TickerFields(mask))
     }
+
+    pub fn iter(self) -> impl Iterator<Item = TickerField> {
+        TickerField::ALL.into_iter().filter(move |f| self.0 & f.bit() != 0)
+    }
+}
+
+/// Failed attempts of one IP within the last minute.
+pub struct FailedAttempts {
+    /// First failure ever seen from this IP, starts the grace window.
+    pub first_seen: u64,
+    pub times: Vec<u64>,
+}
+
+/// Counters and gauges exported by `/metrics.json`.
+#[derive(serde::Serialize)]
+pub struct Metrics {
+    pub uptime_secs: u64,
+    pub ws_connections: usize,
+    pub history_len: usize,
+    pub usd_history_len: usize,
+    pub cache_version: u64,
+    pub limit_bulan: i64,
+    pub blocked_ips: usize,
+    pub failed_attempt_ips: usize,
+    pub dedup_size: usize,
+    pub dedup_evictions: u64,
+    pub integrity_corrections: u64,
+    pub ticks_total: u64,
+    pub usd_updates_total: u64,
+    pub feed_lag: FeedLag,
+    pub usd_fetch: FetchStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_write_wait: Option<LockWaitStats>,
+}
+
+#[derive(Default, serde::Serialize)]
+pub struct Volatility {
+    pub window: usize,
+    pub samples: usize,
+    pub mean_abs_diff: f64,
+    pub max_up: i64,
+    pub max_down: i64,
+    pub up_count: usize,
+    pub down_count: usize,
+    pub flat_count: usize,
+    pub stddev_buy: f64,
+}
+
+/// History overview for `/api/stats`, computed in one pass.
+#[derive(Default, serde::Serialize)]
+pub struct HistorySummary {
+    pub count: usize,
+    pub oldest_created_at: Option<String>,
+    pub newest_created_at: Option<String>,
+    pub current_sell: Option<i64>,
+    /// Over entries stamped within the last 24 hours.
+    pub min_buy_24h: Option<i64>,
+    pub max_buy_24h: Option<i64>,
+    pub avg_diff: f64,
+}
+
+/// `HistorySummary` together with the cache version it was built at.
+struct CachedSummary {
+    version: u64,
+    created_at: Instant,
+    summary: Arc<HistorySummary>,
+}
+
+/// One OHLC bucket of buying rates; `time` is the bucket start in unix seconds.
+#[derive(serde::Serialize)]
+pub struct Candle {
+    pub time: u64,
+    pub open: i64,
+    pub high: i64,
+    pub low: i64,
+    pub close: i64,
 }
 
 // ─── Serialization Helper (manual JSON, zero-copy) ───
@@ -116,6 +462,11 @@ impl JsonWriter {
         self.buf.push(b'"');
     }
 
+    #[inline]
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
     #[inline]
     fn write_i64(&mut self, v: i64) {
         let mut buf = itoa::Buffer::new();
@@ -133,6 +484,41 @@ pub struct CachedState {
     pub data: Bytes,
     pub version: u64,
     pub created_at: Instant,
+    gzip: OnceCell<Bytes>,
+    brotli: OnceCell<Bytes>,
+}
+
+impl CachedState {
+    pub fn new(data: Bytes, version: u64) -> Self {
+        Self {
+            data,
+            version,
+            created_at: Instant::now(),
+            gzip: OnceCell::new(),
+            brotli: OnceCell::new(),
+        }
+    }
+
+    /// `data` gzip-compressed, computed once per cached version.
+    pub fn gzip(&self) -> &Bytes {
+        self.gzip.get_or_init(|| {
+            let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            let _ = enc.write_all(&self.data);
+            Bytes::from(enc.finish().unwrap_or_default())
+        })
+    }
+
+    /// `data` brotli-compressed, computed once per cached version.
+    pub fn brotli(&self) -> &Bytes {
+        self.brotli.get_or_init(|| {
+            let mut out = Vec::with_capacity(self.data.len() / 4);
+            {
+                let mut enc = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                let _ = enc.write_all(&self.data);
+            }
+            Bytes::from(out)
+        })
+    }
 }
 
 // ─── App State ───
@@ -142,43 +528,147 @@ pub struct AppState {
     pub usd_idr_history: RwLock<VecDeque<UsdIdrEntry>>,
     pub last_buy: AtomicI64,
     pub has_last_buy: AtomicBool,
-    pub shown_updates: Mutex<HashSet<String>>,
+    pub shown_updates: Mutex<DedupSet>,
+    pub integrity_corrections: AtomicU64,
+    /// Unix seconds of the last accepted gold tick / USD change, 0 = never.
+    pub last_tick_at: AtomicU64,
+    pub last_usd_update_at: AtomicU64,
+    /// Accepted gold ticks / USD changes since startup.
+    pub ticks_total: AtomicU64,
+    pub usd_updates_total: AtomicU64,
     pub limit_bulan: AtomicI64,
     pub ws_manager: WsManager,
     pub rate_limiter: crate::rate_limiter::RateLimiter,
     pub blocked_ips: DashMap<String, u64>,
-    pub failed_attempts: DashMap<String, Vec<u64>>,
+    pub failed_attempts: DashMap<String, FailedAttempts>,
     pub last_successful_call: AtomicU64,
+    pub tarpit_slots: tokio::sync::Semaphore,
+    pub feed_lag: Mutex<FeedLag>,
+    /// Security events for authenticated `/ws/security` connections.
+    pub admin_events: tokio::sync::broadcast::Sender<Bytes>,
+    pub usd_fetch: Mutex<FetchStats>,
+    /// Only updated with `LOCK_METRICS`.
+    pub history_write_wait: Mutex<LockWaitStats>,
+    started_at: Instant,
     state_cache: ArcSwap<CachedState>,
+    /// Tiers for `?profit=true`, replaceable through `/aturTier`.
+    pub profit_tiers: ArcSwap<ProfitTierSet>,
+    /// Same state with profit tiers, rebuilt only while someone reads it.
+    profit_cache: ArcSwap<CachedState>,
+    ticker_cache: DashMap<TickerFields, Arc<CachedState>>,
+    summary_cache: ArcSwap<CachedSummary>,
     cache_version: AtomicU64,
+    /// Also serializes `publish_now`, so broadcasts leave in version order.
+    patch_tracker: Mutex<PatchTracker>,
+    /// Cadence mode: a broadcast is owed, with the newest tick's frames if any.
+    pending_broadcast: Mutex<Option<(Topics, Option<TickFrames>)>>,
+    /// Cache is fed by `store_external_state` and never rebuilt locally.
+    follower: bool,
 }
 
 impl AppState {
     pub fn new() -> Self {
-        // Pre-build empty state
-        let empty_data = Bytes::from_static(
-            br#"{"history":[],"usd_idr_history":[],"limit_bulan":8}"#
-        );
+        let snap = match LEADER_URL.as_deref() {
+            Some(_) => None,
+            None => DATA_FILE.as_deref().and_then(crate::persistence::load),
+        };
+        Self::with_snapshot(snap)
+    }
 
-        Self {
-            history: RwLock::new(VecDeque::with_capacity(MAX_HISTORY)),
-            usd_idr_history: RwLock::new(VecDeque::with_capacity(MAX_USD_HISTORY)),
+    /// `new` with the history restored from `snap` instead of `DATA_FILE`.
+    pub fn with_snapshot(snap: Option<crate::persistence::Snapshot>) -> Self {
+        let state = Self {
+            history: RwLock::new(VecDeque::with_capacity(*MAX_HISTORY)),
+            usd_idr_history: RwLock::new(VecDeque::with_capacity(if *DISABLE_USD {
+                0
+            } else {
+                MAX_USD_HISTORY
+            })),
             last_buy: AtomicI64::new(0),
             has_last_buy: AtomicBool::new(false),
-            shown_updates: Mutex::new(HashSet::with_capacity(64)),
-            limit_bulan: AtomicI64::new(8),
+            shown_updates: Mutex::new(DedupSet::default()),
+            integrity_corrections: AtomicU64::new(0),
+            last_tick_at: AtomicU64::new(0),
+            last_usd_update_at: AtomicU64::new(0),
+            ticks_total: AtomicU64::new(0),
+            usd_updates_total: AtomicU64::new(0),
+            limit_bulan: AtomicI64::new(*INITIAL_LIMIT_BULAN),
             ws_manager: WsManager::new(),
             rate_limiter: crate::rate_limiter::RateLimiter::new(),
             blocked_ips: DashMap::with_capacity(32),
             failed_attempts: DashMap::with_capacity(32),
             last_successful_call: AtomicU64::new(0),
-            state_cache: ArcSwap::new(Arc::new(CachedState {
-                data: empty_data,
-                version: 0,
+            tarpit_slots: tokio::sync::Semaphore::new(*TARPIT_MAX_CONCURRENT),
+            feed_lag: Mutex::new(FeedLag::default()),
+            admin_events: tokio::sync::broadcast::channel(64).0,
+            usd_fetch: Mutex::new(FetchStats::default()),
+            history_write_wait: Mutex::new(LockWaitStats::default()),
+            started_at: Instant::now(),
+            state_cache: ArcSwap::new(Arc::new(CachedState::new(Bytes::new(), 0))),
+            profit_tiers: ArcSwap::from_pointee(ProfitTierSet::new(default_profit_tiers())),
+            profit_cache: ArcSwap::new(Arc::new(CachedState::new(Bytes::new(), u64::MAX))),
+            ticker_cache: DashMap::with_capacity(4),
+            summary_cache: ArcSwap::from_pointee(CachedSummary {
+                version: u64::MAX,
                 created_at: Instant::now(),
-            })),
+                summary: Arc::default(),
+            }),
             cache_version: AtomicU64::new(0),
+            patch_tracker: Mutex::new(PatchTracker::default()),
+            pending_broadcast: Mutex::new(None),
+            follower: LEADER_URL.is_some(),
+        };
+
+        if let Some(snap) = snap {
+            state.restore(snap);
         }
+
+        // Seed the cache from the real initial values so it never disagrees with config
+        state.state_cache.store(Arc::new(CachedState::new(state.build_full_state_fast(false, *MAX_STATE_BYTES).0, 0)));
+        state
+    }
+
+    /// Installs a snapshot saved by a previous run, trimmed to today's caps.
+    fn restore(&self, snap: crate::persistence::Snapshot) {
+        let mut history = snap.history;
+        while history.len() > *MAX_HISTORY {
+            history.pop_front();
+        }
+        // Treasury replays its latest tick on subscribe; don't record it twice
+        if let Some(last) = history.back() {
+            let ts = utils::parse_created_at(&last.created_at);
+            self.shown_updates.lock().insert(crate::treasury::dedup_key(
+                ts,
+                &last.created_at,
+                last.buying_rate,
+                last.selling_rate,
+            ));
+        }
+        *self.history.write() = history;
+
+        if !*DISABLE_USD {
+            let mut usd = snap.usd_idr_history;
+            while usd.len() > MAX_USD_HISTORY {
+                usd.pop_front();
+            }
+            *self.usd_idr_history.write() = usd;
+        }
+
+        if let Some(buy) = snap.last_buy {
+            self.last_buy.store(buy, Ordering::Relaxed);
+            self.has_last_buy.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn cache_version(&self) -> u64 {
+        self.cache_version.load(Ordering::Acquire)
+    }
+
+    /// True until the first tick arrives or `WARMUP_SECS` have passed.
+    pub fn is_warming_up(&self) -> bool {
+        *WARMUP_SECS > 0
+            && !self.has_last_buy.load(Ordering::Relaxed)
+            && self.started_at.elapsed().as_secs() < *WARMUP_SECS
     }
 
     #[inline]
@@ -186,96 +676,510 @@ impl AppState {
         self.cache_version.fetch_add(1, Ordering::Release);
     }
 
-    pub fn get_cached_state(&self) -> Bytes {
-        let current = self.state_cache.load();
+    /// Current state together with the cache version it was built at.
+    pub fn cached_snapshot(&self) -> Arc<CachedState> {
+        self.snapshots(false).0
+    }
+
+    /// `cached_snapshot`, with the `jt10`..`jt50` profit tiers on every item.
+    pub fn cached_profit_snapshot(&self) -> Arc<CachedState> {
+        let (base, profit) = self.snapshots(true);
+        profit.unwrap_or(base)
+    }
+
+    /// The base snapshot and, with `profit`, the profit-tier one, both at
+    /// the current version. Stale ones are rebuilt together in one pass.
+    fn snapshots(&self, profit: bool) -> (Arc<CachedState>, Option<Arc<CachedState>>) {
+        let base = self.state_cache.load_full();
+        let with_profit = profit.then(|| self.profit_cache.load_full());
+        if self.follower {
+            return (base, with_profit);
+        }
         let ver = self.cache_version.load(Ordering::Acquire);
+        let fresh = |c: &CachedState| {
+            c.version == ver && c.created_at.elapsed().as_millis() < STATE_CACHE_TTL_MS as u128
+        };
+        if fresh(&base) && with_profit.as_deref().is_none_or(fresh) {
+            return (base, with_profit);
+        }
 
-        if current.version == ver
-            && current.created_at.elapsed().as_millis() < STATE_CACHE_TTL_MS as u128
-        {
-            return current.data.clone();
+        let (data, profit_data) = self.build_full_state_fast(profit, *MAX_STATE_BYTES);
+        // A still-fresh base keeps its already compressed encodings
+        let base = if fresh(&base) {
+            base
+        } else {
+            let snap = Arc::new(CachedState::new(data, ver));
+            self.state_cache.store(snap.clone());
+            snap
+        };
+        let with_profit = profit_data.map(|data| {
+            let snap = Arc::new(CachedState::new(data, ver));
+            self.profit_cache.store(snap.clone());
+            snap
+        });
+        (base, with_profit)
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let shown = {
+            let s = self.shown_updates.lock();
+            (s.len(), s.evictions)
+        };
+        Metrics {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            ws_connections: self.ws_manager.count(),
+            history_len: self.history.read().len(),
+            usd_history_len: self.usd_idr_history.read().len(),
+            cache_version: self.cache_version.load(Ordering::Acquire),
+            limit_bulan: self.limit_bulan.load(Ordering::Relaxed),
+            blocked_ips: self.blocked_ips.len(),
+            failed_attempt_ips: self.failed_attempts.len(),
+            dedup_size: shown.0,
+            dedup_evictions: shown.1,
+            integrity_corrections: self.integrity_corrections.load(Ordering::Relaxed),
+            ticks_total: self.ticks_total.load(Ordering::Relaxed),
+            usd_updates_total: self.usd_updates_total.load(Ordering::Relaxed),
+            feed_lag: *self.feed_lag.lock(),
+            usd_fetch: *self.usd_fetch.lock(),
+            history_write_wait: self.history_lock_stats(),
         }
+    }
 
-        let data = self.build_full_state_fast();
+    /// Write lock on `history` for the tick path. With `LOCK_METRICS` an
+    /// uncontended `try_write` costs nothing extra; only a miss is timed.
+    pub fn write_history(&self) -> RwLockWriteGuard<'_, VecDeque<GoldEntry>> {
+        if !*LOCK_METRICS {
+            return self.history.write();
+        }
+        if let Some(guard) = self.history.try_write() {
+            self.history_write_wait.lock().record(None);
+            return guard;
+        }
+        let started = Instant::now();
+        let guard = self.history.write();
+        self.history_write_wait.lock().record(Some(started.elapsed().as_micros() as u64));
+        guard
+    }
 
-        self.state_cache.store(Arc::new(CachedState {
-            data: data.clone(),
-            version: ver,
-            created_at: Instant::now(),
-        }));
+    pub fn history_lock_stats(&self) -> Option<LockWaitStats> {
+        LOCK_METRICS.then(|| *self.history_write_wait.lock())
+    }
+
+    /// Seconds since the timestamp in `at`, `None` if it was never set.
+    /// Clamped to zero if the clock went backward.
+    pub fn seconds_since(at: &AtomicU64) -> Option<u64> {
+        match at.load(Ordering::Relaxed) {
+            0 => None,
+            t => Some(utils::current_timestamp().saturating_sub(t)),
+        }
+    }
+
+    /// Follower mode: installs the leader's `/api/state` without (`base`)
+    /// and with (`profit`) tiers as the cached state under a fresh version,
+    /// so patch tracking still works. `mirror` is `base` decoded, and keeps
+    /// the local history behind `/api/ticker`, `/api/history`, stats and
+    /// warmup in step with the leader.
+    pub fn store_external_state(
+        &self,
+        base: Bytes,
+        profit: Bytes,
+        mirror: crate::persistence::Snapshot,
+        limit_bulan: i64,
+    ) {
+        let mut history = mirror.history;
+        while history.len() > *MAX_HISTORY {
+            history.pop_front();
+        }
+        *self.history.write() = history;
+        *self.usd_idr_history.write() = mirror.usd_idr_history;
+        self.limit_bulan.store(limit_bulan, Ordering::Relaxed);
+        if let Some(buy) = mirror.last_buy {
+            self.last_buy.store(buy, Ordering::Relaxed);
+            self.has_last_buy.store(true, Ordering::Relaxed);
+        }
 
+        let ver = self.cache_version.fetch_add(1, Ordering::AcqRel) + 1;
+        self.state_cache.store(Arc::new(CachedState::new(base, ver)));
+        self.profit_cache.store(Arc::new(CachedState::new(profit, ver)));
+    }
+
+    /// Tiny latest-values payload for polling widgets, cached per field set.
+    pub fn get_ticker(&self, fields: TickerFields) -> Bytes {
+        let ver = self.cache_version.load(Ordering::Acquire);
+
+        if let Some(current) = self.ticker_cache.get(&fields) {
+            if current.version == ver
+                && current.created_at.elapsed().as_millis() < TICKER_CACHE_TTL_MS as u128
+            {
+                return current.data.clone();
+            }
+        }
+
+        let data = self.build_ticker(fields);
+        self.ticker_cache.insert(fields, Arc::new(CachedState::new(data.clone(), ver)));
         data
     }
 
-    /// Fast manual JSON serialization — avoids serde overhead
-    fn build_full_state_fast(&self) -> Bytes {
+    fn build_ticker(&self, fields: TickerFields) -> Bytes {
+        let mut w = JsonWriter::with_capacity(160);
+        let latest = self
+            .history
+            .read()
+            .back()
+            .map(|e| (e.buying_rate, e.selling_rate, e.diff, e.status.clone()));
+        let usd = self.usd_idr_history.read().back().map(|u| u.price.clone());
+
+        w.write_raw(b"{");
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 { w.write_raw(b","); }
+            w.write_str_value(field.name());
+            w.write_raw(b":");
+            match (field, &latest) {
+                (TickerField::Buy, Some((buy, ..))) => w.write_i64(*buy),
+                (TickerField::Sell, Some((_, sell, ..))) => w.write_i64(*sell),
+                (TickerField::Diff, Some((_, _, diff, _))) => w.write_i64(*diff),
+                (TickerField::Status, Some((.., status))) => w.write_str_value(status),
+                (TickerField::Usd, _) => match &usd {
+                    Some(price) => w.write_str_value(price),
+                    None => w.write_raw(b"null"),
+                },
+                (TickerField::Ts, _) => w.write_i64(utils::current_timestamp() as i64),
+                _ => w.write_raw(b"null"),
+            }
+        }
+        w.write_raw(b"}");
+        w.into_bytes()
+    }
+
+    /// Broadcasts the current state to clients subscribed to any of
+    /// `topics`, attaching a JSON patch when any connection asked for patch mode.
+    /// Use `publish_change` after mutating local state.
+    pub fn broadcast_state(&self, topics: Topics) {
+        self.publish(topics, None);
+    }
+
+    /// Invalidates the cache after a state change and broadcasts the result.
+    pub fn publish_change(&self, topics: Topics) {
+        self.invalidate_cache();
+        self.publish(topics, None);
+    }
+
+    /// `publish_change` for a gold tick, also carrying its compact encoding
+    /// and, when `appended` is the only history change, its append frame.
+    pub fn publish_tick(&self, compact: Bytes, appended: Option<&GoldEntry>) {
+        self.invalidate_cache();
+        let append = appended.map(|e| self.append_frame(e, false));
+        let append_profit = appended
+            .filter(|_| self.ws_manager.has_profit_clients())
+            .map(|e| self.append_frame(e, true));
+        self.publish(Topics::GOLD, Some(TickFrames { compact, append, append_profit }));
+    }
+
+    /// `{"type":"append","item":{...},"max_history":N}` for append-mode clients.
+    fn append_frame(&self, e: &GoldEntry, profit: bool) -> Bytes {
+        let tiers = profit.then(|| self.profit_tiers.load_full());
+        let mut w = JsonWriter::with_capacity(600);
+        w.write_raw(b"{\"type\":\"append\",\"item\":");
+        Self::write_item(&mut w, &Self::build_item(e, tiers.as_ref()), profit);
+        w.write_raw(b",\"max_history\":");
+        w.write_i64(*MAX_HISTORY as i64);
+        w.write_raw(b"}");
+        w.into_bytes()
+    }
+
+    fn publish(&self, topics: Topics, tick: Option<TickFrames>) {
+        if *BROADCAST_CADENCE_MS > 0 {
+            let mut pending = self.pending_broadcast.lock();
+            *pending = Some(match pending.take() {
+                None => (topics, tick),
+                // Coalesced: compact clients get the newest tick, but one
+                // append can't stand for several changes, so those get the full state
+                Some((prev_topics, prev)) => (
+                    topics.union(prev_topics),
+                    tick.or(prev).map(|t| TickFrames {
+                        compact: t.compact,
+                        append: None,
+                        append_profit: None,
+                    }),
+                ),
+            });
+            return;
+        }
+        self.publish_now(topics, tick);
+    }
+
+    /// Cadence mode: sends the broadcast coalesced since the last call, if any.
+    pub fn flush_pending_broadcast(&self) {
+        let pending = self.pending_broadcast.lock().take();
+        if let Some((topics, tick)) = pending {
+            self.publish_now(topics, tick);
+        }
+    }
+
+    fn publish_now(&self, topics: Topics, tick: Option<TickFrames>) {
+        // Held from rebuild to send, so a publisher holding an older snapshot
+        // can never broadcast it after a newer one has gone out
+        let mut tracker = self.patch_tracker.lock();
+        let (snap, profit_snap) = self.snapshots(self.ws_manager.has_profit_clients());
+
+        let patch = if self.ws_manager.has_patch_clients() {
+            tracker.advance(snap.version, &snap.data)
+        } else {
+            tracker.reset();
+            None
+        };
+
+        // Compressed once here, however many clients take it
+        let (full_gzip, profit_gzip) = if self.ws_manager.has_compress_clients() {
+            (Some(snap.gzip().clone()), profit_snap.as_ref().map(|p| p.gzip().clone()))
+        } else {
+            (None, None)
+        };
+        let profit = profit_snap.map(|p| p.data.clone());
+        let usd = (topics.intersects(Topics::USD) && self.ws_manager.has_usd_clients())
+            .then(|| self.usd_state());
+
+        let (compact, append, append_profit) = match tick {
+            Some(t) => (Some(t.compact), t.append, t.append_profit),
+            None => (None, None, None),
+        };
+        self.ws_manager.broadcast_frame(Frame {
+            version: Some(snap.version),
+            topics,
+            full: snap.data.clone(),
+            patch,
+            compact,
+            profit,
+            full_gzip,
+            profit_gzip,
+            usd,
+            append,
+            append_profit,
+        });
+    }
+
+    /// Fast manual JSON serialization — avoids serde overhead. Returns the
+    /// base state and, with `profit`, the same state with profit tiers; both
+    /// come from one pass over the history, so the second variant only adds
+    /// the tier math and another write of the shared items. `ceiling` is the
+    /// byte cap, normally `MAX_STATE_BYTES` (0 = unlimited).
+    fn build_full_state_fast(&self, profit: bool, ceiling: usize) -> (Bytes, Option<Bytes>) {
         let history = self.history.read();
-        let usd = self.usd_idr_history.read();
         let limit = self.limit_bulan.load(Ordering::Relaxed);
 
         // Pre-build history items
+        let tiers = profit.then(|| self.profit_tiers.load_full());
         let items: Vec<HistoryItemOwned> = history
             .iter()
-            .map(|h| Self::build_item(h))
+            .map(|h| Self::build_item(h, tiers.as_ref()))
             .collect();
+        drop(history);
 
-        // Estimate capacity: ~500 bytes per history item + ~100 per usd entry
-        let estimated = items.len() * 500 + usd.len() * 100 + 64;
-        let mut w = JsonWriter::with_capacity(estimated);
+        let mut tail = JsonWriter::with_capacity(64);
+        tail.write_raw(b"],\"usd_idr_history\":[");
+        let mut usd_ref = Vec::new();
 
-        // Start object
-        w.write_raw(b"{\"history\":[");
+        if !*DISABLE_USD {
+            let usd = self.usd_idr_history.read();
+            if *VERIFY_SERIALIZER {
+                usd_ref.extend(usd.iter().cloned());
+            }
+            Self::write_usd_entries(&mut tail, &usd);
+        }
 
-        for (i, item) in items.iter().enumerate() {
-            if i > 0 { w.write_raw(b","); }
-            w.write_raw(b"{\"buying_rate\":");
-            w.write_str_value(&item.buying_rate);
-            w.write_raw(b",\"selling_rate\":");
-            w.write_str_value(&item.selling_rate);
-            w.write_raw(b",\"buying_rate_raw\":");
-            w.write_i64(item.buying_rate_raw);
-            w.write_raw(b",\"selling_rate_raw\":");
-            w.write_i64(item.selling_rate_raw);
-            w.write_raw(b",\"waktu_display\":");
-            w.write_str_value(&item.waktu_display);
-            w.write_raw(b",\"diff_display\":");
-            w.write_str_value(&item.diff_display);
-            w.write_raw(b",\"transaction_display\":");
-            w.write_str_value(&item.transaction_display);
-            w.write_raw(b",\"created_at\":");
-            w.write_str_value(&item.created_at);
-            w.write_raw(b",\"jt10\":");
-            w.write_str_value(&item.jt10);
-            w.write_raw(b",\"jt20\":");
-            w.write_str_value(&item.jt20);
-            w.write_raw(b",\"jt30\":");
-            w.write_str_value(&item.jt30);
-            w.write_raw(b",\"jt40\":");
-            w.write_str_value(&item.jt40);
-            w.write_raw(b",\"jt50\":");
-            w.write_str_value(&item.jt50);
-            w.write_raw(b"}");
+        tail.write_raw(b"],\"limit_bulan\":");
+        tail.write_i64(limit);
+        tail.write_raw(b",\"currency\":");
+        tail.write_str_value(&CURRENCY);
+        tail.write_raw(b",\"unit\":");
+        tail.write_str_value(&UNIT);
+
+        let base = Self::render_state(&items, false, &tail.buf, ceiling);
+        let with_profit = profit.then(|| Self::render_state(&items, true, &tail.buf, ceiling));
+
+        if *VERIFY_SERIALIZER {
+            Self::verify_serialization(&base.buf, &items, false, &usd_ref, limit);
+            if let Some(data) = &with_profit {
+                Self::verify_serialization(&data.buf, &items, true, &usd_ref, limit);
+            }
         }
 
-        w.write_raw(b"],\"usd_idr_history\":[");
+        (base.into_bytes(), with_profit.map(JsonWriter::into_bytes))
+    }
 
+    /// `usd` as comma-separated JSON objects, for inside an array.
+    fn write_usd_entries(w: &mut JsonWriter, usd: &VecDeque<UsdIdrEntry>) {
+        w.buf.reserve(usd.len() * 100);
         for (i, entry) in usd.iter().enumerate() {
             if i > 0 { w.write_raw(b","); }
             w.write_raw(b"{\"price\":");
             w.write_str_value(&entry.price);
             w.write_raw(b",\"time\":");
             w.write_str_value(&entry.time);
+            w.write_raw(b",\"time_display\":");
+            w.write_str_value(&entry.time_display);
+            w.write_raw(b",\"source\":");
+            w.write_str_value(&entry.source);
             w.write_raw(b"}");
         }
+    }
 
-        w.write_raw(b"],\"limit_bulan\":");
-        w.write_i64(limit);
-        w.write_raw(b"}");
-
+    /// `{"usd":"16,250.50","usd_idr_history":[...]}` for clients subscribed
+    /// to the `usd` topic alone; `usd` is the latest price, null before the
+    /// first one. Small enough to build per broadcast.
+    pub fn usd_state(&self) -> Bytes {
+        let usd = self.usd_idr_history.read();
+        let mut w = JsonWriter::with_capacity(32 + usd.len() * 100);
+        w.write_raw(b"{\"usd\":");
+        match usd.back() {
+            Some(latest) => w.write_str_value(&latest.price),
+            None => w.write_raw(b"null"),
+        }
+        w.write_raw(b",\"usd_idr_history\":[");
+        Self::write_usd_entries(&mut w, &usd);
+        w.write_raw(b"]}");
         w.into_bytes()
     }
 
-    fn build_item(h: &GoldEntry) -> HistoryItemOwned {
+    /// `{"history":[` + items + `tail`, keeping only the newest items when
+    /// the whole would exceed `ceiling` (0 = unlimited).
+    fn render_state(
+        items: &[HistoryItemOwned],
+        with_profit: bool,
+        tail: &[u8],
+        ceiling: usize,
+    ) -> JsonWriter {
+        // Items go into their own buffer with recorded start offsets so the
+        // output can be cut down to the newest entries that fit the ceiling.
+        // Estimate capacity: ~500 bytes per history item
+        let mut iw = JsonWriter::with_capacity(items.len() * 500);
+        let mut starts = Vec::with_capacity(items.len());
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 { iw.write_raw(b","); }
+            starts.push(iw.len());
+            Self::write_item(&mut iw, item, with_profit);
+        }
+
+        const HEAD: &[u8] = b"{\"history\":[";
+        const TRUNCATED: &[u8] = b",\"truncated\":true}";
+        let items_len = iw.len();
+        let fixed = HEAD.len() + tail.len() + TRUNCATED.len();
+
+        // Keep the newest entries: first start offset whose suffix fits
+        let from = if ceiling > 0 && fixed + items_len > ceiling {
+            starts
+                .iter()
+                .copied()
+                .find(|&s| fixed + items_len - s <= ceiling)
+                .unwrap_or(items_len)
+        } else {
+            0
+        };
+        let truncated = from > 0;
+
+        let mut w = JsonWriter::with_capacity(fixed + items_len - from);
+        w.write_raw(HEAD);
+        w.write_raw(&iw.buf[from..]);
+        w.write_raw(tail);
+        if truncated {
+            w.write_raw(TRUNCATED);
+        } else {
+            w.write_raw(b",\"truncated\":false}");
+        }
+        w
+    }
+
+    /// Compares the fast output with serde_json's rendering of the same data
+    /// and logs loudly on any semantic difference.
+    fn verify_serialization(
+        fast: &[u8],
+        items: &[HistoryItemOwned],
+        with_profit: bool,
+        usd: &[UsdIdrEntry],
+        limit: i64,
+    ) {
+        let parsed: serde_json::Value = match serde_json::from_slice(fast) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("❌ Fast serializer produced invalid JSON: {}", e);
+                return;
+            }
+        };
+
+        let truncated = parsed["truncated"].as_bool().unwrap_or(false);
+        let kept = parsed["history"].as_array().map_or(0, |h| h.len());
+        let kept_items = &items[items.len().saturating_sub(kept)..];
+        let mut history = serde_json::to_value(kept_items).unwrap_or_default();
+        if !with_profit {
+            // Items carry tiers for the profit variant; the base one omits them
+            if let Some(array) = history.as_array_mut() {
+                for (v, item) in array.iter_mut().zip(kept_items) {
+                    if let (Some(obj), Some(p)) = (v.as_object_mut(), &item.profit) {
+                        obj.remove("tiers");
+                        for (label, _) in p.entries() {
+                            obj.remove(label);
+                        }
+                    }
+                }
+            }
+        }
+        let expected = serde_json::json!({
+            "history": history,
+            "usd_idr_history": usd,
+            "limit_bulan": limit,
+            "currency": *CURRENCY,
+            "unit": *UNIT,
+            "truncated": truncated,
+        });
+
+        if parsed != expected || (!truncated && kept != items.len()) {
+            tracing::error!("❌ Fast serializer output differs from serde_json reference");
+        }
+    }
+
+    /// Field changes here must be mirrored in `src/schema/state.json`.
+    /// Profit tiers are written only with `with_profit`.
+    fn write_item(w: &mut JsonWriter, item: &HistoryItemOwned, with_profit: bool) {
+        w.write_raw(b"{\"buying_rate\":");
+        w.write_str_value(&item.buying_rate);
+        w.write_raw(b",\"selling_rate\":");
+        w.write_str_value(&item.selling_rate);
+        w.write_raw(b",\"buying_rate_raw\":");
+        w.write_i64(item.buying_rate_raw);
+        w.write_raw(b",\"selling_rate_raw\":");
+        w.write_i64(item.selling_rate_raw);
+        w.write_raw(b",\"waktu_display\":");
+        w.write_str_value(&item.waktu_display);
+        w.write_raw(b",\"diff_display\":");
+        w.write_str_value(&item.diff_display);
+        w.write_raw(b",\"transaction_display\":");
+        w.write_str_value(&item.transaction_display);
+        w.write_raw(b",\"created_at\":");
+        w.write_str_value(&item.created_at);
+        w.write_raw(b",\"source\":");
+        w.write_str_value(&item.source);
+        if let Some(extra) = &item.extra {
+            w.write_raw(b",\"extra\":");
+            w.write_raw(&serde_json::to_vec(extra).unwrap_or_else(|_| b"{}".to_vec()));
+        }
+        if let Some(p) = item.profit.as_ref().filter(|_| with_profit) {
+            if !p.set.legacy {
+                w.write_raw(b",\"tiers\":{");
+            }
+            for (i, (label, value)) in p.entries().enumerate() {
+                if i > 0 || p.set.legacy { w.write_raw(b","); }
+                w.write_str_value(label);
+                w.write_raw(b":");
+                w.write_str_value(value);
+            }
+            if !p.set.legacy {
+                w.write_raw(b"}");
+            }
+        }
+        w.write_raw(b"}");
+    }
+
+    fn build_item(h: &GoldEntry, tiers: Option<&Arc<ProfitTierSet>>) -> HistoryItemOwned {
         let buy_fmt = utils::format_rupiah(h.buying_rate);
         let sell_fmt = utils::format_rupiah(h.selling_rate);
         let diff_display = utils::format_diff_display(h.diff, &h.status);
@@ -292,16 +1196,224 @@ impl AppState {
             diff_display,
             transaction_display,
             created_at: h.created_at.clone(),
-            jt10: utils::calc_profit(h.buying_rate, h.selling_rate, 10_000_000, 9_669_000),
-            jt20: utils::calc_profit(h.buying_rate, h.selling_rate, 20_000_000, 19_330_000),
-            jt30: utils::calc_profit(h.buying_rate, h.selling_rate, 30_000_000, 28_995_000),
-            jt40: utils::calc_profit(h.buying_rate, h.selling_rate, 40_000_000, 38_660_000),
-            jt50: utils::calc_profit(h.buying_rate, h.selling_rate, 50_000_000, 48_325_000),
+            source: h.source.clone(),
+            extra: h.extra.clone(),
+            profit: tiers.map(|set| ProfitValues {
+                set: set.clone(),
+                values: set
+                    .tiers
+                    .iter()
+                    .map(|t| utils::calc_profit(h.buying_rate, h.selling_rate, t.modal, t.pokok))
+                    .collect(),
+            }),
+        }
+    }
+
+    /// Entry `n` positions back from the newest (0 = latest).
+    pub fn entry_at_offset(&self, n: usize) -> Option<GoldEntry> {
+        let history = self.history.read();
+        if n >= history.len() {
+            return None;
+        }
+        history.get(history.len() - 1 - n).cloned()
+    }
+
+    /// One entry in the same JSON shape as the items in `/api/state`,
+    /// with profit tiers only when `profit` is set.
+    pub fn item_json(&self, e: &GoldEntry, profit: bool) -> Bytes {
+        let tiers = profit.then(|| self.profit_tiers.load_full());
+        let mut w = JsonWriter::with_capacity(512);
+        Self::write_item(&mut w, &Self::build_item(e, tiers.as_ref()), profit);
+        w.into_bytes()
+    }
+
+    /// `entries` as a JSON array of `/api/state` items, see `item_json`.
+    pub fn items_json(&self, entries: &[GoldEntry], profit: bool) -> Bytes {
+        let tiers = profit.then(|| self.profit_tiers.load_full());
+        let mut w = JsonWriter::with_capacity(entries.len() * 500 + 2);
+        w.write_raw(b"[");
+        for (i, e) in entries.iter().enumerate() {
+            if i > 0 { w.write_raw(b","); }
+            Self::write_item(&mut w, &Self::build_item(e, tiers.as_ref()), profit);
+        }
+        w.write_raw(b"]");
+        w.into_bytes()
+    }
+
+    /// Entries matching `filter`, newest first, at most `limit` (capped at `MAX_HISTORY`).
+    pub fn filtered_history(&self, filter: &HistoryFilter) -> Vec<GoldEntry> {
+        let limit = filter.limit.unwrap_or(*MAX_HISTORY).min(*MAX_HISTORY);
+        self.history
+            .read()
+            .iter()
+            .rev()
+            .filter(|e| filter.matches(e))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Net buying-rate movement between the newest entry and the one `window`
+    /// ticks earlier (or the oldest available when history is shorter).
+    pub fn trend(&self, window: usize) -> (&'static str, i64) {
+        let history = self.history.read();
+        let len = history.len();
+        if len < 2 {
+            return ("flat", 0);
+        }
+
+        let newest = history[len - 1].buying_rate;
+        let base = history[len - 1 - window.min(len - 1)].buying_rate;
+        let change = newest - base;
+
+        let trend = match change.cmp(&0) {
+            std::cmp::Ordering::Greater => "up",
+            std::cmp::Ordering::Less => "down",
+            std::cmp::Ordering::Equal => "flat",
+        };
+        (trend, change)
+    }
+
+    /// Summary statistics over the newest `window` entries in a single pass.
+    pub fn volatility(&self, window: usize) -> Volatility {
+        let window = window.clamp(1, *MAX_HISTORY);
+        let history = self.history.read();
+        let skip = history.len().saturating_sub(window);
+
+        let mut v = Volatility { window, ..Default::default() };
+        let mut abs_sum = 0i64;
+        // Welford's running mean/variance
+        let mut mean = 0f64;
+        let mut m2 = 0f64;
+
+        for e in history.iter().skip(skip) {
+            v.samples += 1;
+            abs_sum += e.diff.abs();
+            match e.diff.cmp(&0) {
+                std::cmp::Ordering::Greater => {
+                    v.up_count += 1;
+                    v.max_up = v.max_up.max(e.diff);
+                }
+                std::cmp::Ordering::Less => {
+                    v.down_count += 1;
+                    v.max_down = v.max_down.min(e.diff);
+                }
+                std::cmp::Ordering::Equal => v.flat_count += 1,
+            }
+
+            let x = e.buying_rate as f64;
+            let delta = x - mean;
+            mean += delta / v.samples as f64;
+            m2 += delta * (x - mean);
+        }
+
+        if v.samples > 0 {
+            v.mean_abs_diff = abs_sum as f64 / v.samples as f64;
+        }
+        if v.samples > 1 {
+            v.stddev_buy = (m2 / v.samples as f64).sqrt();
+        }
+        v
+    }
+
+    /// `HistorySummary`, rebuilt when the cache version moves.
+    pub fn summary(&self) -> Arc<HistorySummary> {
+        let ver = self.cache_version.load(Ordering::Acquire);
+        let current = self.summary_cache.load();
+        if current.version == ver
+            && current.created_at.elapsed().as_millis() < SUMMARY_CACHE_TTL_MS as u128
+        {
+            return current.summary.clone();
+        }
+
+        let summary = Arc::new(self.build_summary());
+        self.summary_cache.store(Arc::new(CachedSummary {
+            version: ver,
+            created_at: Instant::now(),
+            summary: summary.clone(),
+        }));
+        summary
+    }
+
+    fn build_summary(&self) -> HistorySummary {
+        let history = self.history.read();
+        let since = utils::current_timestamp().saturating_sub(86_400);
+        let mut s = HistorySummary {
+            count: history.len(),
+            oldest_created_at: history.front().map(|e| e.created_at.clone()),
+            newest_created_at: history.back().map(|e| e.created_at.clone()),
+            current_sell: history.back().map(|e| e.selling_rate),
+            ..Default::default()
+        };
+
+        let mut diff_sum = 0i64;
+        for e in history.iter() {
+            diff_sum += e.diff;
+            if utils::parse_created_at(&e.created_at).is_some_and(|ts| ts >= since) {
+                s.min_buy_24h = Some(s.min_buy_24h.map_or(e.buying_rate, |m| m.min(e.buying_rate)));
+                s.max_buy_24h = Some(s.max_buy_24h.map_or(e.buying_rate, |m| m.max(e.buying_rate)));
+            }
         }
+        if s.count > 0 {
+            s.avg_diff = diff_sum as f64 / s.count as f64;
+        }
+        s
     }
 
+    /// Buying rates grouped into `interval`-second buckets, oldest first.
+    /// Entries whose `created_at` does not parse are skipped.
+    pub fn candles(&self, interval: u64) -> Vec<Candle> {
+        let mut points: Vec<(u64, i64)> = self
+            .history
+            .read()
+            .iter()
+            .filter_map(|e| Some((utils::parse_created_at(&e.created_at)?, e.buying_rate)))
+            .collect();
+        // Stable, so ticks sharing a timestamp keep arrival order for open/close
+        points.sort_by_key(|&(ts, _)| ts);
+
+        let mut candles: Vec<Candle> = Vec::new();
+        for (ts, buy) in points {
+            let time = ts / interval * interval;
+            match candles.last_mut() {
+                Some(c) if c.time == time => {
+                    c.high = c.high.max(buy);
+                    c.low = c.low.min(buy);
+                    c.close = buy;
+                }
+                _ => candles.push(Candle { time, open: buy, high: buy, low: buy, close: buy }),
+            }
+        }
+        candles
+    }
+
+    /// The candle holding the newest entry, as the last of `candles(interval)`
+    /// would be, scanning only as far back as that bucket reaches.
+    pub fn latest_candle(&self, interval: u64) -> Option<Candle> {
+        let history = self.history.read();
+        let mut points = history
+            .iter()
+            .rev()
+            .filter_map(|e| Some((utils::parse_created_at(&e.created_at)?, e.buying_rate)));
+        let (ts, close) = points.next()?;
+        let time = ts / interval * interval;
+        let mut candle = Candle { time, open: close, high: close, low: close, close };
+        for (ts, buy) in points.take_while(|&(ts, _)| ts >= time) {
+            if ts < time + interval {
+                candle.high = candle.high.max(buy);
+                candle.low = candle.low.min(buy);
+                candle.open = buy;
+            }
+        }
+        Some(candle)
+    }
+
+    /// Blocklist and failed attempts are keyed by `normalize_ip_key`, so an
+    /// IPv6 block covers the client's whole /64.
     #[inline]
     pub fn is_ip_blocked(&self, ip: &str) -> bool {
+        let ip = normalize_ip_key(ip);
+        let ip = ip.as_str();
         if let Some(entry) = self.blocked_ips.get(ip) {
             let now = utils::current_timestamp();
             if now < *entry {
@@ -309,32 +1421,217 @@ impl AppState {
             }
             drop(entry);
             self.blocked_ips.remove(ip);
-            self.failed_attempts.remove(ip);
+            if *FAILED_GRACE_SECS > 0 {
+                // Keep first_seen so a returning offender doesn't get a fresh grace window
+                if let Some(mut attempts) = self.failed_attempts.get_mut(ip) {
+                    attempts.times.clear();
+                }
+            } else {
+                self.failed_attempts.remove(ip);
+            }
+            self.emit_admin_event("ip_unblocked", ip);
         }
         false
     }
 
     #[inline]
     pub fn block_ip(&self, ip: &str, duration: u64) {
-        self.blocked_ips
-            .insert(ip.to_string(), utils::current_timestamp() + duration);
+        let ip = normalize_ip_key(ip);
+        self.emit_admin_event("ip_blocked", &ip);
+        self.blocked_ips.insert(ip, utils::current_timestamp() + duration);
+    }
+
+    fn emit_admin_event(&self, kind: &str, ip: &str) {
+        if self.admin_events.receiver_count() == 0 {
+            return;
+        }
+        let event = serde_json::json!({"type": kind, "ip": ip});
+        let _ = self.admin_events.send(Bytes::from(event.to_string()));
     }
 
     pub fn record_failed_attempt(&self, ip: &str, weight: usize) {
         let now = utils::current_timestamp();
+        let key = normalize_ip_key(ip);
         let mut entry = self
             .failed_attempts
-            .entry(ip.to_string())
-            .or_insert_with(|| Vec::with_capacity(MAX_FAILED_ATTEMPTS));
+            .entry(key.clone())
+            .or_insert_with(|| FailedAttempts {
+                first_seen: now,
+                times: Vec::with_capacity(MAX_FAILED_ATTEMPTS),
+            });
 
         for _ in 0..weight {
-            entry.push(now);
+            entry.times.push(now);
         }
-        entry.retain(|&t| now - t < 60);
+        entry.times.retain(|&t| now - t < 60);
 
-        if entry.len() >= MAX_FAILED_ATTEMPTS {
+        let in_grace = *FAILED_GRACE_SECS > 0 && now.saturating_sub(entry.first_seen) < *FAILED_GRACE_SECS;
+        let threshold = if in_grace {
+            *FAILED_GRACE_MAX_ATTEMPTS
+        } else {
+            MAX_FAILED_ATTEMPTS
+        };
+
+        if entry.times.len() >= threshold {
             drop(entry);
-            self.block_ip(ip, BLOCK_DURATION_SECS);
+            self.block_ip(&key, RATE_LIMIT.block_secs);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(buy: i64, created_at: String) -> GoldEntry {
+        GoldEntry {
+            buying_rate: buy,
+            selling_rate: buy - 20_000,
+            status: "➖".into(),
+            diff: 0,
+            created_at,
+            source: SOURCE_LIVE.into(),
+            extra: None,
+        }
+    }
+
+    /// `n` entries one minute apart, oldest first.
+    fn entries(n: usize) -> VecDeque<GoldEntry> {
+        (0..n)
+            .map(|i| {
+                let ts = 1_700_000_000 + i as u64 * 60;
+                entry(1_500_000 + i as i64, utils::format_wib(ts, "%Y-%m-%d %H:%M:%S"))
+            })
+            .collect()
+    }
+
+    fn parse(data: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(data).expect("state is valid JSON")
+    }
+
+    #[test]
+    fn latest_candle_matches_last_candle() {
+        let state = AppState::with_snapshot(None);
+        *state.history.write() = entries(12);
+        for interval in [60, 300, 3600] {
+            let last = state.candles(interval).pop().expect("history has candles");
+            let latest = state.latest_candle(interval).expect("history has a newest entry");
+            assert_eq!(
+                (latest.time, latest.open, latest.high, latest.low, latest.close),
+                (last.time, last.open, last.high, last.low, last.close),
+                "interval {interval}"
+            );
+        }
+    }
+
+    #[test]
+    fn history_filters_by_source() {
+        let mut history = entries(4);
+        history[1].source = SOURCE_BACKFILL.into();
+        history[3].source = SOURCE_BACKFILL.into();
+        let state = AppState::with_snapshot(None);
+        *state.history.write() = history;
+
+        let only = |source: Option<&str>| {
+            let filter = HistoryFilter { source: source.map(Into::into), ..Default::default() };
+            state.filtered_history(&filter).iter().map(|e| e.buying_rate).collect::<Vec<_>>()
+        };
+        assert_eq!(only(Some(SOURCE_BACKFILL)), [1_500_003, 1_500_001]);
+        assert_eq!(only(Some(SOURCE_LIVE)), [1_500_002, 1_500_000]);
+        assert_eq!(only(None).len(), 4);
+        assert!(only(Some("nope")).is_empty());
+    }
+
+    #[test]
+    fn external_state_fills_history_and_ends_warmup() {
+        let state = AppState::with_snapshot(None);
+        let history = entries(3);
+        let mirror = crate::persistence::Snapshot {
+            last_buy: history.back().map(|e| e.buying_rate),
+            history,
+            usd_idr_history: VecDeque::new(),
+        };
+        let base = Bytes::from_static(b"{\"base\":true}");
+        let profit = Bytes::from_static(b"{\"profit\":true}");
+        state.store_external_state(base.clone(), profit.clone(), mirror, 9);
+
+        assert_eq!(state.history.read().len(), 3);
+        assert!(state.has_last_buy.load(Ordering::Relaxed));
+        assert_eq!(state.last_buy.load(Ordering::Relaxed), 1_500_002);
+        assert_eq!(state.limit_bulan.load(Ordering::Relaxed), 9);
+        assert_eq!(state.state_cache.load().data, base);
+        assert_eq!(state.profit_cache.load().data, profit);
+    }
+
+    #[test]
+    fn first_served_state_reflects_config() {
+        let snap = crate::persistence::Snapshot {
+            history: entries(*MAX_HISTORY + 10),
+            usd_idr_history: VecDeque::new(),
+            last_buy: None,
+        };
+        let state = AppState::with_snapshot(Some(snap));
+
+        let v = parse(&state.cached_snapshot().data);
+        assert_eq!(v["limit_bulan"], *INITIAL_LIMIT_BULAN);
+        assert_eq!(v["history"].as_array().unwrap().len(), *MAX_HISTORY);
+    }
+
+    #[test]
+    fn state_over_ceiling_keeps_newest_entries() {
+        let state = AppState::with_snapshot(None);
+        *state.history.write() = entries(300);
+        let newest = state.history.read().back().unwrap().created_at.clone();
+
+        let data = state.build_full_state_fast(false, 20_000).0;
+        assert!(data.len() <= 20_000);
+        let v = parse(&data);
+        assert_eq!(v["truncated"], true);
+        let kept = v["history"].as_array().unwrap();
+        assert!(!kept.is_empty() && kept.len() < 300);
+        assert_eq!(kept.last().unwrap()["created_at"], newest.as_str());
+
+        let full = parse(&state.build_full_state_fast(false, 0).0);
+        assert_eq!(full["truncated"], false);
+        assert_eq!(full["history"].as_array().unwrap().len(), 300);
+    }
+
+    #[test]
+    fn profit_tiers_only_where_asked_for() {
+        let state = AppState::with_snapshot(None);
+        *state.history.write() = entries(3);
+
+        let (base, profit) = state.build_full_state_fast(true, 0);
+        let base = parse(&base);
+        let profit = parse(&profit.unwrap());
+        assert!(base["history"][0].get("jt10").is_none());
+        assert!(profit["history"][0]["jt10"].is_string());
+        assert_eq!(base["history"][0]["transaction_display"], profit["history"][0]["transaction_display"]);
+
+        let e = state.history.read()[0].clone();
+        assert!(parse(&state.item_json(&e, false)).get("jt10").is_none());
+        assert!(parse(&state.item_json(&e, true))["jt50"].is_string());
+        assert!(parse(&state.items_json(&[e], false))[0].get("jt10").is_none());
+    }
+
+    #[test]
+    fn usd_state_carries_only_usd() {
+        let state = AppState::with_snapshot(None);
+        *state.history.write() = entries(3);
+        assert_eq!(parse(&state.usd_state()), serde_json::json!({"usd": null, "usd_idr_history": []}));
+
+        for price in ["16,250.00", "16,251.50"] {
+            state.usd_idr_history.write().push_back(UsdIdrEntry {
+                price: price.into(),
+                time: "2024-05-01 10:00:00".into(),
+                time_display: "10:00:00".into(),
+                source: "google".into(),
+            });
         }
+        let v = parse(&state.usd_state());
+        assert_eq!(v["usd"], "16,251.50");
+        assert_eq!(v["usd_idr_history"].as_array().unwrap().len(), 2);
+        assert!(v.get("history").is_none());
     }
 }
@@ -134,11 +134,11 @@ function gTRI(h){if(!h||!h.length)return'';var s=h.slice().sort(function(a,b){re
 function tBE(){if(blinkT)clearTimeout(blinkT);var fr=$('#tabel tbody tr:first-child');if(!fr.length)return;fr.removeClass('blink-row');void fr[0].offsetWidth;fr.addClass('blink-row');blinkT=setTimeout(function(){fr.removeClass('blink-row');blinkT=null},2000)}
 function rT(){var h=latestHistory;if(!h||!h.length)return;var nid=gTRI(h),isNew=nid!==lastTopRowId;if(isNew)lastTopRowId=nid;h.sort(function(a,b){return new Date(b.created_at)-new Date(a.created_at)});var k=gOPK();uTH();var arr=h.map(function(d){return{waktu:d.waktu_display,transaction:'Beli: '+d.buying_rate+' Jual: '+d.selling_rate+''+d.diff_display,p1:d[k[0]],p2:d[k[1]],p3:d[k[2]],p4:d[k[3]],p5:d[k[4]]}});table.clear().rows.add(arr).draw(false);table.page('first').draw(false);if(isNew&&!isFirstRender)setTimeout(tBE,50);if(isFirstRender)isFirstRender=false}
 function uT(h){if(!h||!h.length)return;latestHistory=h;h.sort(function(a,b){return new Date(b.created_at)-new Date(a.created_at)});if(h[0]&&h[0].buying_rate_raw&&h[0].selling_rate_raw){latestBuyRate=h[0].buying_rate_raw;latestSellRate=h[0].selling_rate_raw;uCR()}rT()}
-function uU(h){var c=document.getElementById('currentPrice'),p=document.getElementById('priceList');if(!h||!h.length){c.textContent='Menunggu data...';c.className='loading-text';p.innerHTML='<li class="loading-text">Menunggu data...</li>';return}c.className='';function ps(s){return parseFloat(s.trim().replace(/\./g,'').replace(',','.'))}var r=h.slice().reverse();var ic='➖';if(r.length>1){var n=ps(r[0].price),pr=ps(r[1].price);ic=n>pr?'🚀':n<pr?'🔻':'➖'}c.innerHTML=r[0].price+' '+ic;var ht='';for(var i=0;i<r.length;i++){var ico='➖';if(i===0&&r.length>1){var n=ps(r[0].price),pr=ps(r[1].price);ico=n>pr?'🟢':n<pr?'🔴':'➖'}else if(i<r.length-1){var n=ps(r[i].price),nx=ps(r[i+1].price);ico=n>nx?'🟢':n<nx?'🔴':'➖'}else if(r.length>1){var n=ps(r[i].price),pr=ps(r[i-1].price);ico=n<pr?'🔴':n>pr?'🟢':'➖'}ht+='<li>'+r[i].price+' <span class="time">('+r[i].time+')</span> '+ico+'</li>'}p.innerHTML=ht}
-function pM(d){if(d.ping)return;if(d.history)uT(d.history);if(d.usd_idr_history)uU(d.usd_idr_history);if(d.limit_bulan!==undefined)document.getElementById('limitBulan').textContent=d.limit_bulan}
+function uU(h){var c=document.getElementById('currentPrice'),p=document.getElementById('priceList');if(!h||!h.length){c.textContent='Menunggu data...';c.className='loading-text';p.innerHTML='<li class="loading-text">Menunggu data...</li>';return}c.className='';function ps(s){return parseFloat(s.trim().replace(/\./g,'').replace(',','.'))}var r=h.slice().reverse();var ic='➖';if(r.length>1){var n=ps(r[0].price),pr=ps(r[1].price);ic=n>pr?'🚀':n<pr?'🔻':'➖'}c.innerHTML=r[0].price+' '+ic;var ht='';for(var i=0;i<r.length;i++){var ico='➖';if(i===0&&r.length>1){var n=ps(r[0].price),pr=ps(r[1].price);ico=n>pr?'🟢':n<pr?'🔴':'➖'}else if(i<r.length-1){var n=ps(r[i].price),nx=ps(r[i+1].price);ico=n>nx?'🟢':n<nx?'🔴':'➖'}else if(r.length>1){var n=ps(r[i].price),pr=ps(r[i-1].price);ico=n<pr?'🔴':n>pr?'🟢':'➖'}ht+='<li>'+r[i].price+' <span class="time">('+(r[i].time_display||r[i].time)+')</span> '+ico+'</li>'}p.innerHTML=ht}
+function pM(d){if(d.ping||d.type==='selftest')return;if(d.history)uT(d.history);if(d.usd_idr_history)uU(d.usd_idr_history);if(d.limit_bulan!==undefined)document.getElementById('limitBulan').textContent=d.limit_bulan}
 function pQ(){if(isP||!mq.length)return;isP=true;try{pM(mq.shift())}catch(e){}isP=false;if(mq.length)requestAnimationFrame(pQ)}
 var ws,ra=0,pi;
-function conn(){var pr=location.protocol==='https:'?'wss:':'ws:';ws=new WebSocket(pr+'//'+location.host+'/ws');ws.binaryType='arraybuffer';ws.onopen=function(){ra=0;if(pi)clearInterval(pi);pi=setInterval(function(){if(ws&&ws.readyState===1)try{ws.send('ping')}catch(e){}},25000)};ws.onmessage=function(e){try{var d;if(e.data instanceof ArrayBuffer){d=JSON.parse(new TextDecoder().decode(e.data))}else{d=JSON.parse(e.data)}mq.push(d);requestAnimationFrame(pQ)}catch(x){}};ws.onclose=function(){if(pi)clearInterval(pi);ra++;setTimeout(conn,Math.min(1000*Math.pow(1.3,ra-1),15000))};ws.onerror=function(){}}
+function conn(){var pr=location.protocol==='https:'?'wss:':'ws:';ws=new WebSocket(pr+'//'+location.host+'/ws?profit=true');ws.binaryType='arraybuffer';ws.onopen=function(){ra=0;if(pi)clearInterval(pi);pi=setInterval(function(){if(ws&&ws.readyState===1)try{ws.send('ping')}catch(e){}},25000)};ws.onmessage=function(e){try{var d;if(e.data instanceof ArrayBuffer){d=JSON.parse(new TextDecoder().decode(e.data))}else{d=JSON.parse(e.data)}mq.push(d);requestAnimationFrame(pQ)}catch(x){}};ws.onclose=function(){if(pi)clearInterval(pi);ra++;setTimeout(conn,Math.min(1000*Math.pow(1.3,ra-1),15000))};ws.onerror=function(){}}
 conn();
 function uJ(){var n=new Date(),ds=['Minggu','Senin','Selasa','Rabu','Kamis','Jumat','Sabtu'];document.getElementById('jam').textContent=ds[n.getDay()]+', '+n.toLocaleTimeString('id-ID',{hour12:false})+' WIB'}
 setInterval(uJ,1000);uJ();
@@ -149,3 +149,10 @@ setTimeout(cTVW,100);
 </script>
 </body>
 </html>"##;
+
+
+/// Extra static pages served as-is, path → HTML.
+pub const STATIC_PAGES: &[(&str, &str)] = &[
+    ("/about", include_str!("pages/about.html")),
+    ("/status", include_str!("pages/status.html")),
+];
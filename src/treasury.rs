@@ -1,10 +1,19 @@
 use futures_util::{SinkExt, StreamExt};
-use std::sync::atomic::Ordering;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, info, warn};
 
+use crate::alerts;
 use crate::config::*;
-use crate::state::{AppState, GoldEntry};
+use crate::state::{AppState, GoldEntry, SOURCE_BACKFILL, SOURCE_LIVE};
+use crate::rng;
+use crate::utils;
+use crate::ws_manager::{self, Topics};
+
+static TICK_SEQ: AtomicU64 = AtomicU64::new(0);
 
 #[derive(serde::Deserialize)]
 struct PusherMessage {
@@ -14,22 +23,185 @@ struct PusherMessage {
     channel: Option<String>,
 }
 
+#[derive(serde::Deserialize)]
+struct ConnectionEstablished {
+    socket_id: String,
+    activity_timeout: Option<u64>,
+}
+
 #[derive(serde::Deserialize)]
 struct GoldRateData {
     buying_rate: Option<serde_json::Value>,
     selling_rate: Option<serde_json::Value>,
     created_at: Option<String>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Pusher sends `data` as a JSON-encoded string, but accept a plain object too.
+fn decode_data<T: serde::de::DeserializeOwned>(dv: serde_json::Value) -> Option<T> {
+    match dv {
+        serde_json::Value::String(s) => serde_json::from_str(&s).ok(),
+        other => serde_json::from_value(other).ok(),
+    }
 }
 
 fn parse_number(v: &serde_json::Value) -> Option<i64> {
     match v {
-        serde_json::Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
-        serde_json::Value::String(s) => s.replace('.', "").replace(',', "").parse().ok(),
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .or_else(|| n.as_f64().map(|f| ROUNDING_MODE.apply(f))),
+        serde_json::Value::String(s) => s.replace(['.', ','], "").parse().ok(),
         _ => None,
     }
 }
 
-async fn process_data(state: &Arc<AppState>, data: GoldRateData) {
+fn check_rates(buy: i64, sell: i64) -> Result<(), &'static str> {
+    let range = *MIN_RATE..=*MAX_RATE;
+    if !range.contains(&buy) {
+        return Err("buying_rate out of range");
+    }
+    if !range.contains(&sell) {
+        return Err("selling_rate out of range");
+    }
+    if *REJECT_INVERTED_SPREAD && sell > buy {
+        return Err("selling_rate above buying_rate");
+    }
+    Ok(())
+}
+
+/// Dedup key from the parsed timestamp plus both rates: the same tick with a
+/// differently formatted `created_at` is dropped, while distinct values
+/// within the same second are kept. Falls back to the raw string if unparseable.
+pub fn dedup_key(created_ts: Option<u64>, created_at: &str, buy: i64, sell: i64) -> String {
+    match created_ts {
+        Some(ts) => format!("{}|{}|{}", ts, buy, sell),
+        None => format!("{}|{}|{}", created_at.trim(), buy, sell),
+    }
+}
+
+/// True when `buy`/`sell` both equal the newest entry, whatever its `created_at`.
+fn repeats_last(history: &VecDeque<GoldEntry>, buy: i64, sell: i64) -> bool {
+    history.back().is_some_and(|e| e.buying_rate == buy && e.selling_rate == sell)
+}
+
+/// With `skip_unchanged` (`SKIP_UNCHANGED_TICKS`), true for a tick that
+/// repeats the newest entry: it is not recorded, but still counts as the
+/// last seen buying rate.
+fn skip_unchanged_tick(state: &AppState, buy: i64, sell: i64, skip_unchanged: bool) -> bool {
+    if !skip_unchanged || !repeats_last(&state.history.read(), buy, sell) {
+        return false;
+    }
+    state.last_buy.store(buy, Ordering::Relaxed);
+    state.has_last_buy.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Status emoji for a buying-rate move.
+pub fn status_for(diff: i64) -> &'static str {
+    match diff.signum() {
+        1 => "🚀",
+        -1 => "🔻",
+        _ => "➖",
+    }
+}
+
+/// Recomputes diff/status of every entry against its predecessor and fixes
+/// mismatches. The oldest entry is skipped since its predecessor is gone.
+/// Returns the number of corrected entries.
+pub fn check_integrity(state: &AppState) -> usize {
+    let consistent = |history: &VecDeque<GoldEntry>, i: usize| {
+        let diff = history[i].buying_rate - history[i - 1].buying_rate;
+        history[i].diff == diff && history[i].status == status_for(diff)
+    };
+
+    // Cheap read-only pass first; the write lock is only taken when needed
+    {
+        let history = state.history.read();
+        if (1..history.len()).all(|i| consistent(&history, i)) {
+            return 0;
+        }
+    }
+
+    let mut corrected = 0;
+    {
+        let mut history = state.history.write();
+        for i in 1..history.len() {
+            if consistent(&history, i) {
+                continue;
+            }
+            let diff = history[i].buying_rate - history[i - 1].buying_rate;
+            warn!(
+                "🔧 Fixed entry at {}: diff {} → {}, status {} → {}",
+                history[i].created_at,
+                history[i].diff,
+                diff,
+                history[i].status,
+                status_for(diff)
+            );
+            history[i].diff = diff;
+            history[i].status = status_for(diff).to_string();
+            corrected += 1;
+        }
+    }
+
+    if corrected > 0 {
+        state.integrity_corrections.fetch_add(corrected as u64, Ordering::Relaxed);
+        state.publish_change(Topics::GOLD);
+    }
+    corrected
+}
+
+/// Drops the middle of every flat run longer than `keep` entries, keeping
+/// the first `keep - 1` and the last, then recomputes diff/status against
+/// the retained neighbours. Returns the number of entries removed.
+pub fn compact_flat_runs(history: &mut VecDeque<GoldEntry>, keep: usize) -> usize {
+    let flat: Vec<bool> = (0..history.len())
+        .map(|i| i > 0 && history[i].buying_rate == history[i - 1].buying_rate)
+        .collect();
+
+    let mut drop = vec![false; history.len()];
+    let mut i = 0;
+    while i < flat.len() {
+        if !flat[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < flat.len() && flat[i] {
+            i += 1;
+        }
+        if i - start > keep {
+            drop[start + keep - 1..i - 1].fill(true);
+        }
+    }
+
+    let before = history.len();
+    let mut idx = 0;
+    history.retain(|_| {
+        let keep = !drop[idx];
+        idx += 1;
+        keep
+    });
+
+    for i in 1..history.len() {
+        let diff = history[i].buying_rate - history[i - 1].buying_rate;
+        if history[i].diff != diff {
+            history[i].diff = diff;
+            history[i].status = status_for(diff).to_string();
+        }
+    }
+    before - history.len()
+}
+
+pub async fn integrity_loop(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(*INTEGRITY_CHECK_SECS)).await;
+        check_integrity(&state);
+    }
+}
+
+async fn process_data(state: &Arc<AppState>, data: GoldRateData, source: &str) {
     let buy = match data.buying_rate.as_ref().and_then(parse_number) {
         Some(v) => v,
         None => return,
@@ -43,57 +215,163 @@ async fn process_data(state: &Arc<AppState>, data: GoldRateData) {
         _ => return,
     };
 
-    {
-        let mut shown = state.shown_updates.lock();
-        if shown.contains(&created_at) {
-            return;
-        }
-        shown.insert(created_at.clone());
-        if shown.len() > 5000 {
-            let keep = created_at.clone();
-            shown.clear();
-            shown.insert(keep);
-        }
+    let extra = if *PASSTHROUGH_EXTRA_FIELDS && !data.extra.is_empty() {
+        Some(data.extra)
+    } else {
+        None
+    };
+
+    if let Err(reason) = check_rates(buy, sell) {
+        warn!("Rejected tick at {}: {} (buy {} sell {})", created_at, reason, buy, sell);
+        return;
+    }
+
+    let created_ts = utils::parse_created_at(&created_at);
+    let key = dedup_key(created_ts, &created_at, buy, sell);
+
+    if !state.shown_updates.lock().insert(key) {
+        return;
+    }
+
+    // Backfilled ticks are late by design and would skew the lag stats
+    if let (Some(ts), true) = (created_ts, source == SOURCE_LIVE) {
+        state.feed_lag.lock().record(ts, utils::current_timestamp_ms());
+    }
+    state.last_tick_at.store(utils::current_timestamp(), Ordering::Relaxed);
+    state.ticks_total.fetch_add(1, Ordering::Relaxed);
+
+    if skip_unchanged_tick(state, buy, sell, *SKIP_UNCHANGED_TICKS) {
+        debug!("unchanged tick skipped at {}", created_at);
+        return;
     }
 
     let has_last = state.has_last_buy.load(Ordering::Relaxed);
     let last = state.last_buy.load(Ordering::Relaxed);
 
-    let (status, diff) = if !has_last {
-        ("➖".into(), 0i64)
-    } else if buy > last {
-        ("🚀".into(), buy - last)
-    } else if buy < last {
-        ("🔻".into(), buy - last)
-    } else {
-        ("➖".into(), 0i64)
-    };
+    let diff = if has_last { buy - last } else { 0 };
+    let status = status_for(diff).to_string();
+
+    let seq = TICK_SEQ.fetch_add(1, Ordering::Relaxed);
+    if diff.abs() >= *SIGNIFICANT_MOVE {
+        info!("{} Significant move {:+} → buy {} sell {} at {}", status, diff, buy, sell, created_at);
+    } else if seq.is_multiple_of(*TICK_LOG_EVERY) {
+        debug!("tick #{} buy {} sell {} diff {:+} at {}", seq, buy, sell, diff, created_at);
+    }
+
+    // Backfilled moves are already stale by the time we see them
+    if has_last && source == SOURCE_LIVE {
+        alerts::check_move(last, buy, &created_at);
+    }
 
+    let compact = ws_manager::compact_tick(
+        buy,
+        sell,
+        diff,
+        &status,
+        created_ts.unwrap_or_else(utils::current_timestamp),
+    );
+
+    let entry = GoldEntry {
+        buying_rate: buy,
+        selling_rate: sell,
+        status,
+        diff,
+        created_at,
+        source: source.into(),
+        extra,
+    };
+    let mut compacted = false;
     {
-        let mut history = state.history.write();
-        if history.len() >= MAX_HISTORY {
+        let mut history = state.write_history();
+        if history.len() >= *MAX_HISTORY && *COMPACT_FLAT_RUNS > 0 {
+            let removed = compact_flat_runs(&mut history, *COMPACT_FLAT_RUNS);
+            if removed > 0 {
+                debug!("🗜️ Compacted {} flat entries from history", removed);
+                compacted = true;
+            }
+        }
+        if history.len() >= *MAX_HISTORY {
             history.pop_front();
         }
-        history.push_back(GoldEntry {
-            buying_rate: buy,
-            selling_rate: sell,
-            status,
-            diff,
-            created_at,
-        });
+        history.push_back(entry.clone());
     }
 
     state.last_buy.store(buy, Ordering::Relaxed);
     state.has_last_buy.store(true, Ordering::Relaxed);
-    state.invalidate_cache();
-    state.ws_manager.broadcast(state.get_cached_state());
+    // Append clients can't replay a compaction, so they get the full state then
+    state.publish_tick(compact, (!compacted).then_some(&entry));
+}
+
+/// Fetches `url` and replays every tick newer than the newest stored one,
+/// oldest first, so diffs chain onto the existing history. Ticks already
+/// seen live are dropped by the usual `shown_updates` dedup.
+async fn backfill(state: &Arc<AppState>, client: &reqwest::Client, url: &str) {
+    let since = match state.history.read().back().and_then(|e| utils::parse_created_at(&e.created_at)) {
+        Some(ts) => ts,
+        None => return,
+    };
+
+    let body: serde_json::Value = match client.get(url).header("Accept", "application/json").send().await {
+        Ok(resp) if resp.status().is_success() => {
+            match resp.bytes().await.map(|b| serde_json::from_slice(&b)) {
+                Ok(Ok(v)) => v,
+                _ => {
+                    warn!("Backfill response from {} is not valid JSON", url);
+                    return;
+                }
+            }
+        }
+        Ok(resp) => {
+            warn!("Backfill from {} failed: HTTP {}", url, resp.status());
+            return;
+        }
+        Err(e) => {
+            warn!("Backfill from {} failed: {}", url, e);
+            return;
+        }
+    };
+    let items = match body {
+        serde_json::Value::Array(items) => items,
+        serde_json::Value::Object(mut obj) => match obj.remove("data") {
+            Some(serde_json::Value::Array(items)) => items,
+            _ => return,
+        },
+        _ => return,
+    };
+
+    let mut missed: Vec<(u64, GoldRateData)> = items
+        .into_iter()
+        .filter_map(|v| serde_json::from_value::<GoldRateData>(v).ok())
+        .filter_map(|g| {
+            let ts = utils::parse_created_at(g.created_at.as_deref()?)?;
+            (ts > since).then_some((ts, g))
+        })
+        .collect();
+    if missed.is_empty() {
+        return;
+    }
+    missed.sort_by_key(|&(ts, _)| ts);
+
+    info!("↩️ Backfilling {} ticks missed since the last connection", missed.len());
+    for (_, g) in missed {
+        process_data(state, g, SOURCE_BACKFILL).await;
+    }
 }
 
 pub async fn treasury_ws_loop(state: Arc<AppState>) {
     let mut errors: u32 = 0;
+    let backfill_client = TREASURY_HISTORY_URL.as_deref().filter(|_| *BACKFILL).map(|url| {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .connect_timeout(Duration::from_secs(5))
+            .gzip(true)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+        (client, url)
+    });
 
     loop {
-        match connect_async(TREASURY_WS_URL).await {
+        match connect_async(TREASURY_WS_URL.as_str()).await {
             Ok((ws, _)) => {
                 errors = 0;
                 let (mut write, mut read) = ws.split();
@@ -110,23 +388,71 @@ pub async fn treasury_ws_loop(state: Arc<AppState>) {
                     continue;
                 }
 
-                while let Some(Ok(msg)) = read.next().await {
+                // Live frames queue up on the socket meanwhile and are deduped after
+                if let Some((client, url)) = &backfill_client {
+                    backfill(&state, client, url).await;
+                }
+
+                let mut socket_id = String::from("-");
+                let mut activity = Duration::from_secs(PUSHER_DEFAULT_ACTIVITY_SECS);
+                let mut awaiting_pong = false;
+
+                loop {
+                    // Quiet for a full activity window: ping, then give up if no answer
+                    let wait = if awaiting_pong {
+                        Duration::from_secs(PUSHER_PONG_TIMEOUT_SECS)
+                    } else {
+                        activity
+                    };
+                    let msg = match tokio::time::timeout(wait, read.next()).await {
+                        Ok(Some(Ok(msg))) => msg,
+                        Ok(_) => break,
+                        Err(_) if awaiting_pong => {
+                            warn!("Pusher pong timeout on socket {}, reconnecting", socket_id);
+                            break;
+                        }
+                        Err(_) => {
+                            debug!("Pusher idle on socket {}, sending ping", socket_id);
+                            let ping = r#"{"event":"pusher:ping","data":{}}"#;
+                            if write.send(Message::Text(ping.into())).await.is_err() {
+                                break;
+                            }
+                            awaiting_pong = true;
+                            continue;
+                        }
+                    };
+                    awaiting_pong = false;
+
                     match msg {
                         Message::Text(text) => {
-                            if let Ok(pm) = serde_json::from_str::<PusherMessage>(&text) {
-                                if pm.event.as_deref() == Some(TREASURY_EVENT) {
-                                    if let Some(dv) = pm.data {
-                                        let gd: Option<GoldRateData> = match dv {
-                                            serde_json::Value::String(s) => {
-                                                serde_json::from_str(&s).ok()
-                                            }
-                                            other => serde_json::from_value(other).ok(),
-                                        };
-                                        if let Some(g) = gd {
-                                            process_data(&state, g).await;
+                            let pm = match serde_json::from_str::<PusherMessage>(&text) {
+                                Ok(pm) => pm,
+                                Err(_) => continue,
+                            };
+                            match (pm.event.as_deref(), pm.data) {
+                                (Some(TREASURY_EVENT), Some(dv)) => {
+                                    if let Some(g) = decode_data::<GoldRateData>(dv) {
+                                        process_data(&state, g, SOURCE_LIVE).await;
+                                    }
+                                }
+                                (Some("pusher:connection_established"), Some(dv)) => {
+                                    if let Some(ce) = decode_data::<ConnectionEstablished>(dv) {
+                                        if let Some(secs) = ce.activity_timeout.filter(|&s| s > 0) {
+                                            activity = Duration::from_secs(secs);
                                         }
+                                        socket_id = ce.socket_id;
+                                        info!(
+                                            "Pusher connected, socket {} (activity timeout {}s)",
+                                            socket_id,
+                                            activity.as_secs()
+                                        );
                                     }
                                 }
+                                (Some("pusher:ping"), _) => {
+                                    let pong = r#"{"event":"pusher:pong","data":{}}"#;
+                                    let _ = write.send(Message::Text(pong.into())).await;
+                                }
+                                _ => {}
                             }
                         }
                         Message::Ping(d) => {
@@ -136,13 +462,226 @@ pub async fn treasury_ws_loop(state: Arc<AppState>) {
                         _ => {}
                     }
                 }
+                debug!("Pusher socket {} closed", socket_id);
             }
-            Err(_) => {
+            Err(e) => {
                 errors += 1;
+                warn!("Treasury connect failed ({} in a row): {}", errors, e);
             }
         }
 
-        let wait = std::cmp::min(errors as u64, 15);
-        tokio::time::sleep(tokio::time::Duration::from_secs(wait)).await;
+        // Jitter keeps replicas from reconnecting in lockstep after an outage
+        let backoff_ms = std::cmp::min(errors as u64, 15) * 1000;
+        let wait_ms = backoff_ms + rng::rng().below(RECONNECT_JITTER_MS);
+        tokio::time::sleep(tokio::time::Duration::from_millis(wait_ms)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tick(buy: serde_json::Value, sell: serde_json::Value, created_at: &str) -> GoldRateData {
+        serde_json::from_value(json!({
+            "buying_rate": buy,
+            "selling_rate": sell,
+            "created_at": created_at,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rates_at_the_bounds_are_accepted() {
+        assert!(check_rates(*MIN_RATE, *MIN_RATE).is_ok());
+        assert!(check_rates(*MAX_RATE, *MAX_RATE).is_ok());
+        assert!(check_rates(*MIN_RATE - 1, *MIN_RATE).is_err());
+        assert!(check_rates(*MAX_RATE + 1, *MAX_RATE).is_err());
+        assert!(check_rates(*MAX_RATE, *MIN_RATE - 1).is_err());
+    }
+
+    #[test]
+    fn corrupt_rates_are_rejected() {
+        assert!(check_rates(0, 0).is_err());
+        assert!(check_rates(-1_500_000, -1_520_000).is_err());
+        assert!(check_rates(i64::MAX, 1_480_000).is_err());
+        assert!(check_rates(1_500_000, 1_520_000).is_err(), "sell above buy");
+    }
+
+    #[test]
+    fn parse_number_handles_corrupt_payloads() {
+        assert_eq!(parse_number(&json!(1_500_000)), Some(1_500_000));
+        assert_eq!(parse_number(&json!("1.500.000")), Some(1_500_000));
+        assert_eq!(parse_number(&json!("abc")), None);
+        assert_eq!(parse_number(&json!("")), None);
+        assert_eq!(parse_number(&json!(null)), None);
+        assert_eq!(parse_number(&json!(true)), None);
+        assert_eq!(parse_number(&json!({"v": 1})), None);
+        assert_eq!(parse_number(&json!("99999999999999999999")), None);
+        // Saturates instead of wrapping, so the range check still catches it
+        assert_eq!(parse_number(&json!(1e30)), Some(i64::MAX));
+    }
+
+    #[tokio::test]
+    async fn corrupt_ticks_are_not_recorded() {
+        let state = Arc::new(AppState::new());
+        let at = "2024-05-01 10:00:00";
+        process_data(&state, tick(json!(0), json!(0), at), SOURCE_LIVE).await;
+        process_data(&state, tick(json!(-5), json!(-5), at), SOURCE_LIVE).await;
+        process_data(&state, tick(json!(1e30), json!(1_480_000), at), SOURCE_LIVE).await;
+        process_data(&state, tick(json!(1_500_000), json!(1_520_000), at), SOURCE_LIVE).await;
+        process_data(&state, tick(json!("abc"), json!(1_480_000), at), SOURCE_LIVE).await;
+        assert!(state.history.read().is_empty());
+        assert!(!state.has_last_buy.load(Ordering::Relaxed));
+
+        process_data(&state, tick(json!(1_500_000), json!(1_480_000), at), SOURCE_LIVE).await;
+        assert_eq!(state.history.read().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn identical_tick_in_another_format_is_deduped() {
+        let state = Arc::new(AppState::new());
+        let formats = ["2024-05-01 10:00:00", "2024-05-01T10:00:00.000+07:00", "2024-05-01T03:00:00Z"];
+        for at in formats {
+            process_data(&state, tick(json!(1_500_000), json!(1_480_000), at), SOURCE_LIVE).await;
+        }
+        assert_eq!(state.history.read().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn changed_tick_in_the_same_second_is_kept() {
+        let state = Arc::new(AppState::new());
+        let at = "2024-05-01 10:00:00";
+        process_data(&state, tick(json!(1_500_000), json!(1_480_000), at), SOURCE_LIVE).await;
+        process_data(&state, tick(json!(1_501_000), json!(1_480_000), at), SOURCE_LIVE).await;
+        process_data(&state, tick(json!(1_501_000), json!(1_481_000), at), SOURCE_LIVE).await;
+
+        let history = state.history.read();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].diff, 1_000);
+    }
+
+    #[tokio::test]
+    async fn unchanged_tick_detection() {
+        let state = Arc::new(AppState::new());
+        assert!(!repeats_last(&state.history.read(), 1_500_000, 1_480_000));
+
+        let same = |at: &str| tick(json!(1_500_000), json!(1_480_000), at);
+        process_data(&state, same("2024-05-01 10:00:00"), SOURCE_LIVE).await;
+        assert!(repeats_last(&state.history.read(), 1_500_000, 1_480_000));
+        assert!(!repeats_last(&state.history.read(), 1_500_000, 1_481_000));
+        assert!(!repeats_last(&state.history.read(), 1_501_000, 1_480_000));
+
+    }
+
+    #[tokio::test]
+    async fn unchanged_tick_is_skipped_only_when_enabled() {
+        let state = Arc::new(AppState::new());
+        let first = tick(json!(1_500_000), json!(1_480_000), "2024-05-01 10:00:00");
+        process_data(&state, first, SOURCE_LIVE).await;
+        let len = state.history.read().len();
+        state.last_buy.store(0, Ordering::Relaxed);
+        state.has_last_buy.store(false, Ordering::Relaxed);
+
+        assert!(!skip_unchanged_tick(&state, 1_500_000, 1_480_000, false));
+        assert!(!state.has_last_buy.load(Ordering::Relaxed), "disabled: nothing touched");
+        assert!(!skip_unchanged_tick(&state, 1_500_000, 1_481_000, true), "sell moved");
+
+        assert!(skip_unchanged_tick(&state, 1_500_000, 1_480_000, true));
+        assert_eq!(state.history.read().len(), len, "no entry added");
+        assert!(state.has_last_buy.load(Ordering::Relaxed));
+        assert_eq!(state.last_buy.load(Ordering::Relaxed), 1_500_000);
+    }
+
+    /// One entry per buying rate, a minute apart, with diff/status filled
+    /// in as `process_data` would.
+    fn history_of(buys: &[i64]) -> VecDeque<GoldEntry> {
+        buys.iter()
+            .enumerate()
+            .map(|(i, &buy)| {
+                let diff = if i == 0 { 0 } else { buy - buys[i - 1] };
+                GoldEntry {
+                    buying_rate: buy,
+                    selling_rate: buy - 20_000,
+                    status: status_for(diff).to_string(),
+                    diff,
+                    created_at: format!("2024-05-01 10:{:02}:00", i),
+                    source: SOURCE_LIVE.into(),
+                    extra: None,
+                }
+            })
+            .collect()
+    }
+
+    /// Minutes (original positions) of the entries still in `history`.
+    fn minutes(history: &VecDeque<GoldEntry>) -> Vec<usize> {
+        history.iter().map(|e| e.created_at[14..16].parse().unwrap()).collect()
+    }
+
+    fn assert_diffs_follow_neighbours(history: &VecDeque<GoldEntry>) {
+        for i in 1..history.len() {
+            let diff = history[i].buying_rate - history[i - 1].buying_rate;
+            assert_eq!(history[i].diff, diff, "diff at {}", i);
+            assert_eq!(history[i].status, status_for(diff), "status at {}", i);
+        }
+    }
+
+    #[test]
+    fn integrity_check_fixes_only_inconsistent_entries() {
+        let state = AppState::new();
+        *state.history.write() = history_of(&[100, 150, 120, 120, 200]);
+        let before = state.integrity_corrections.load(Ordering::Relaxed);
+        assert_eq!(check_integrity(&state), 0);
+        assert_eq!(state.integrity_corrections.load(Ordering::Relaxed), before);
+
+        {
+            let mut h = state.history.write();
+            h[1].diff = 10;
+            h[3].status = "🚀".into();
+        }
+        assert_eq!(check_integrity(&state), 2);
+        assert_eq!(state.integrity_corrections.load(Ordering::Relaxed), before + 2);
+        let h = state.history.read();
+        assert_eq!((h[1].diff, h[1].status.as_str()), (50, "🚀"));
+        assert_eq!((h[3].diff, h[3].status.as_str()), (0, "➖"));
+        assert_diffs_follow_neighbours(&h);
+    }
+
+    #[test]
+    fn long_flat_run_is_thinned_keeping_its_ends() {
+        let mut h = history_of(&[100, 200, 200, 200, 200, 200, 200, 300]);
+        assert_eq!(compact_flat_runs(&mut h, 3), 2);
+        assert_eq!(minutes(&h), [0, 1, 2, 3, 6, 7]);
+        assert_diffs_follow_neighbours(&h);
+    }
+
+    #[test]
+    fn flat_run_of_exactly_keep_is_untouched() {
+        let mut h = history_of(&[100, 200, 200, 200, 200, 300]);
+        assert_eq!(compact_flat_runs(&mut h, 3), 0);
+        assert_eq!(minutes(&h), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn flat_runs_at_front_and_tail_are_thinned() {
+        let mut front = history_of(&[200, 200, 200, 200, 200, 300]);
+        assert_eq!(compact_flat_runs(&mut front, 2), 2);
+        assert_eq!(minutes(&front), [0, 1, 4, 5]);
+
+        let mut tail = history_of(&[100, 200, 200, 200, 200, 200]);
+        assert_eq!(compact_flat_runs(&mut tail, 2), 2);
+        assert_eq!(minutes(&tail), [0, 1, 2, 5]);
+        assert_eq!(tail.back().map(|e| e.buying_rate), Some(200));
+    }
+
+    #[test]
+    fn compaction_leaves_diffs_matching_retained_neighbours() {
+        let mut h = history_of(&[100, 100, 100, 100, 150, 150, 150, 150, 120, 120, 120]);
+        // A stale diff on a kept entry is corrected along the way
+        h[8].diff = 7;
+        h[8].status = "🚀".into();
+        assert_eq!(compact_flat_runs(&mut h, 2), 2);
+        assert_eq!(minutes(&h), [0, 1, 3, 4, 5, 7, 8, 9, 10]);
+        assert_diffs_follow_neighbours(&h);
     }
 }
\ No newline at end of file
@@ -1,65 +1,374 @@
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use scraper::{Html, Selector};
+use std::future::Future;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
 use crate::config::*;
 use crate::state::{AppState, UsdIdrEntry};
 use crate::utils;
+use crate::ws_manager::Topics;
 
-async fn fetch_price(client: &reqwest::Client) -> Option<String> {
+/// USD/IDR price sources, tried in declaration order until one answers
+/// (`USD_FETCH_CONCURRENCY` at a time).
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum UsdSource {
+    Google,
+    ErApi,
+    Yahoo,
+}
+
+const SOURCES: [UsdSource; 3] = [UsdSource::Google, UsdSource::ErApi, UsdSource::Yahoo];
+
+impl UsdSource {
+    fn name(self) -> &'static str {
+        match self {
+            UsdSource::Google => "google",
+            UsdSource::ErApi => "er-api",
+            UsdSource::Yahoo => "yahoo",
+        }
+    }
+
+    /// Backups are polled far less often than the scraper: their data moves
+    /// slowly and free APIs throttle aggressive clients.
+    fn min_interval(self) -> Duration {
+        match self {
+            UsdSource::Google => Duration::ZERO,
+            UsdSource::ErApi => Duration::from_secs(60),
+            UsdSource::Yahoo => Duration::from_secs(5),
+        }
+    }
+
+    async fn fetch(self, client: &reqwest::Client) -> Result<Option<String>, reqwest::Error> {
+        match self {
+            UsdSource::Google => fetch_google(client).await,
+            UsdSource::ErApi => {
+                let v = fetch_json(client, "https://open.er-api.com/v6/latest/USD").await?;
+                Ok(v.and_then(|v| v["rates"]["IDR"].as_f64()).map(format_price))
+            }
+            UsdSource::Yahoo => {
+                let url = "https://query1.finance.yahoo.com/v8/finance/chart/USDIDR=X";
+                let v = fetch_json(client, url).await?;
+                Ok(v.and_then(|v| v["chart"]["result"][0]["meta"]["regularMarketPrice"].as_f64())
+                    .map(format_price))
+            }
+        }
+    }
+}
+
+/// Formats like Google Finance does, e.g. `16,250.50`.
+fn format_price(v: f64) -> String {
+    let s = format!("{:.2}", v);
+    let (int, frac) = s.split_once('.').unwrap_or((&s, "00"));
+    format!("{}.{}", utils::format_rupiah(int.parse().unwrap_or(0)).replace('.', ","), frac)
+}
+
+async fn fetch_json(client: &reqwest::Client, url: &str) -> Result<Option<serde_json::Value>, reqwest::Error> {
+    let resp = client.get(url).header("Accept", "application/json").send().await?;
+    if resp.status() != 200 {
+        return Ok(None);
+    }
+    let text = resp.text().await?;
+    Ok(serde_json::from_str(&text).ok())
+}
+
+async fn fetch_google(client: &reqwest::Client) -> Result<Option<String>, reqwest::Error> {
     let resp = client
         .get("https://www.google.com/finance/quote/USD-IDR")
         .header("Accept", "text/html,application/xhtml+xml")
         .header("Cookie", "CONSENT=YES+cb.20231208-04-p0.en+FX+410")
         .send()
-        .await
-        .ok()?;
+        .await?;
 
     if resp.status() != 200 {
-        return None;
+        return Ok(None);
     }
 
-    let text = resp.text().await.ok()?;
+    let text = resp.text().await?;
     let doc = Html::parse_document(&text);
-    let sel = Selector::parse("div.YMlKec.fxKbKc").ok()?;
+    let sel = match Selector::parse("div.YMlKec.fxKbKc") {
+        Ok(sel) => sel,
+        Err(_) => return Ok(None),
+    };
 
-    doc.select(&sel)
+    Ok(doc
+        .select(&sel)
         .next()
         .map(|el| el.text().collect::<String>().trim().to_string())
+        .filter(|p| !p.is_empty()))
+}
+
+/// Consecutive failures of one source, after which it warns once.
+const FAILURE_WARN_AFTER: u32 = 5;
+
+/// Per-source polling schedule. A failing source backs off on its own, so
+/// an outage of the scraper doesn't keep hammering it every round while a
+/// backup answers.
+#[derive(Clone, Copy, Default)]
+struct SourceSchedule {
+    next_at: Option<Instant>,
+    failures: u32,
+}
+
+impl SourceSchedule {
+    fn due(&self, now: Instant) -> bool {
+        self.next_at.is_none_or(|t| now >= t)
+    }
+
+    fn record(&mut self, source: UsdSource, ok: bool, now: Instant) {
+        if ok {
+            if self.failures >= FAILURE_WARN_AFTER {
+                info!("✅ USD/IDR {} recovered after {} failures", source.name(), self.failures);
+            }
+            self.failures = 0;
+            self.next_at = Some(now + source.min_interval());
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.failures == FAILURE_WARN_AFTER {
+            warn!("⚠️ USD/IDR {} failed {} times in a row, backing off", source.name(), self.failures);
+        }
+        // Doubles per failure, capped, and never sooner than the source allows
+        let backoff = Duration::from_millis(
+            USD_POLL_INTERVAL_MS
+                .saturating_mul(1u64 << self.failures.min(16))
+                .min(USD_MAX_BACKOFF_MS),
+        );
+        self.next_at = Some(now + backoff.max(source.min_interval()));
+    }
+}
+
+/// Outcome of one polling round.
+enum Fetched {
+    Price(String, UsdSource),
+    /// Every due source was tried and none had a price.
+    Missed,
+    /// Like `Missed`, but at least one source timed out.
+    TimedOut,
+    /// All sources are backing off or inside their `min_interval`, so no
+    /// request went out; not a failed fetch.
+    NothingDue,
+}
+
+/// First source that yields a price, skipping sources that are not due yet.
+async fn fetch_price(
+    client: &reqwest::Client,
+    schedule: &mut [SourceSchedule; SOURCES.len()],
+    concurrency: usize,
+) -> Fetched {
+    race_sources(schedule, concurrency, |source| source.fetch(client)).await
+}
+
+/// Keeps up to `concurrency` due sources in flight, in priority order,
+/// starting the next one whenever one fails. The first price wins; the
+/// others are dropped mid-request and their schedules left untouched, so a
+/// cancelled source counts neither as a success nor as a failure.
+async fn race_sources<F, Fut>(
+    schedule: &mut [SourceSchedule; SOURCES.len()],
+    concurrency: usize,
+    fetch: F,
+) -> Fetched
+where
+    F: Fn(UsdSource) -> Fut,
+    Fut: Future<Output = Result<Option<String>, reqwest::Error>>,
+{
+    let now = Instant::now();
+    let due: Vec<usize> = (0..SOURCES.len()).filter(|&i| schedule[i].due(now)).collect();
+    if due.is_empty() {
+        return Fetched::NothingDue;
+    }
+    let mut due = due.into_iter();
+    let start = |i: usize| {
+        let fut = fetch(SOURCES[i]);
+        async move { (i, fut.await) }
+    };
+    let mut racing: FuturesUnordered<_> =
+        due.by_ref().take(concurrency.max(1)).map(start).collect();
+
+    let mut timed_out = false;
+    while let Some((i, result)) = racing.next().await {
+        schedule[i].record(SOURCES[i], matches!(result, Ok(Some(_))), Instant::now());
+        match result {
+            Ok(Some(price)) => return Fetched::Price(price, SOURCES[i]),
+            Ok(None) => {}
+            Err(e) => timed_out |= e.is_timeout(),
+        }
+        if let Some(next) = due.next() {
+            racing.push(start(next));
+        }
+    }
+    if timed_out {
+        Fetched::TimedOut
+    } else {
+        Fetched::Missed
+    }
+}
+
+/// Appends `price` unless it equals the newest entry, which after a restart
+/// may be the restored one. True when it was recorded and published.
+fn record_price(state: &AppState, price: String, source: UsdSource) -> bool {
+    let unchanged = state.usd_idr_history.read().back().is_some_and(|e| e.price == price);
+    if unchanged {
+        return false;
+    }
+
+    let mut h = state.usd_idr_history.write();
+    if h.len() >= MAX_USD_HISTORY {
+        h.pop_front();
+    }
+    let (time, time_display) = utils::current_wib_time();
+    h.push_back(UsdIdrEntry {
+        price,
+        time,
+        time_display,
+        source: source.name().to_string(),
+    });
+    drop(h);
+    state.last_usd_update_at.store(utils::current_timestamp(), Ordering::Relaxed);
+    state.usd_updates_total.fetch_add(1, Ordering::Relaxed);
+
+    state.publish_change(Topics::USD);
+    true
 }
 
 pub async fn usd_idr_loop(state: Arc<AppState>) {
+    let (timeout_secs, connect_timeout_secs) = *USD_TIMEOUTS;
     let client = reqwest::Client::builder()
         .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36")
-        .timeout(std::time::Duration::from_secs(30))
-        .connect_timeout(std::time::Duration::from_secs(10))
+        .timeout(std::time::Duration::from_secs(timeout_secs))
+        .connect_timeout(std::time::Duration::from_secs(connect_timeout_secs))
         .gzip(true)
         .pool_max_idle_per_host(5)
         .build()
         .unwrap_or_else(|_| reqwest::Client::new());
 
+    let mut schedule = [SourceSchedule::default(); SOURCES.len()];
+    let mut active = SOURCES[0];
+
     loop {
-        if let Some(price) = fetch_price(&client).await {
-            let should_update = {
-                let h = state.usd_idr_history.read();
-                h.is_empty() || h.back().map(|e| &e.price) != Some(&price)
-            };
-
-            if should_update {
-                let mut h = state.usd_idr_history.write();
-                if h.len() >= MAX_USD_HISTORY {
-                    h.pop_front();
+        let started = std::time::Instant::now();
+        let result = fetch_price(&client, &mut schedule, *USD_FETCH_CONCURRENCY).await;
+        if !matches!(result, Fetched::NothingDue) {
+            state.usd_fetch.lock().record(
+                started.elapsed().as_millis() as u64,
+                matches!(result, Fetched::Price(..)),
+                matches!(result, Fetched::TimedOut),
+            );
+        }
+
+        if let Fetched::Price(price, source) = result {
+            if source != active {
+                if source == SOURCES[0] {
+                    info!("✅ USD/IDR back on {}", source.name());
+                } else {
+                    warn!("⚠️ USD/IDR falling back to {} (from {})", source.name(), active.name());
                 }
-                h.push_back(UsdIdrEntry {
-                    price,
-                    time: utils::current_wib_time(),
-                });
-                drop(h);
-
-                state.invalidate_cache();
-                state.ws_manager.broadcast(state.get_cached_state());
+                active = source;
             }
+            record_price(&state, price, source);
         }
 
         tokio::time::sleep(tokio::time::Duration::from_millis(USD_POLL_INTERVAL_MS)).await;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Google answers after `google_ms` with `google`, er-api at once with
+    /// a price, Yahoo never gets a price.
+    async fn fake(
+        source: UsdSource,
+        google_ms: u64,
+        google: Option<&'static str>,
+    ) -> Result<Option<String>, reqwest::Error> {
+        match source {
+            UsdSource::Google => {
+                tokio::time::sleep(Duration::from_millis(google_ms)).await;
+                Ok(google.map(String::from))
+            }
+            UsdSource::ErApi => Ok(Some("16,300.00".into())),
+            UsdSource::Yahoo => Ok(None),
+        }
+    }
+
+    #[test]
+    fn restored_price_is_not_recorded_again() {
+        let restored = UsdIdrEntry {
+            price: "16,250.00".into(),
+            time: "2024-05-01 09:59:00".into(),
+            time_display: "09:59:00".into(),
+            source: "google".into(),
+        };
+        let snap = crate::persistence::Snapshot {
+            history: Default::default(),
+            usd_idr_history: [restored].into(),
+            last_buy: None,
+        };
+        let state = AppState::with_snapshot(Some(snap));
+
+        assert!(!record_price(&state, "16,250.00".into(), UsdSource::Google));
+        assert_eq!(state.usd_idr_history.read().len(), 1);
+        assert_eq!(state.usd_updates_total.load(Ordering::Relaxed), 0);
+
+        assert!(record_price(&state, "16,260.00".into(), UsdSource::ErApi));
+        assert_eq!(state.usd_idr_history.read().len(), 2);
+        assert_eq!(state.usd_updates_total.load(Ordering::Relaxed), 1);
+    }
+
+    fn price(fetched: Fetched) -> Option<(String, &'static str)> {
+        match fetched {
+            Fetched::Price(price, source) => Some((price, source.name())),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sequential_falls_back_in_order() {
+        let mut schedule = [SourceSchedule::default(); SOURCES.len()];
+        let got = race_sources(&mut schedule, 1, |s| fake(s, 0, None)).await;
+
+        assert_eq!(price(got), Some(("16,300.00".into(), "er-api")));
+        assert_eq!(schedule[0].failures, 1);
+        assert_eq!(schedule[1].failures, 0);
+        assert!(schedule[1].next_at.is_some());
+        assert!(schedule[2].next_at.is_none(), "yahoo is never reached");
+    }
+
+    #[tokio::test]
+    async fn a_slow_source_does_not_hold_up_the_race() {
+        let mut schedule = [SourceSchedule::default(); SOURCES.len()];
+        let started = Instant::now();
+        let slow_google = |s| fake(s, 5_000, Some("16,250.00"));
+        let got = race_sources(&mut schedule, 2, slow_google).await;
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(price(got).map(|(_, s)| s), Some("er-api"));
+        // Cancelled, not failed: google stays due with a clean record
+        assert_eq!(schedule[0].failures, 0);
+        assert!(schedule[0].next_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn sources_not_due_are_skipped() {
+        let mut schedule = [SourceSchedule::default(); SOURCES.len()];
+        schedule[1].next_at = Some(Instant::now() + Duration::from_secs(60));
+        let got = race_sources(&mut schedule, 3, |s| fake(s, 0, None)).await;
+
+        assert!(matches!(got, Fetched::Missed));
+        assert_eq!((schedule[0].failures, schedule[1].failures, schedule[2].failures), (1, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn a_round_with_nothing_due_sends_nothing() {
+        let later = Instant::now() + Duration::from_secs(60);
+        let mut schedule = [SourceSchedule { next_at: Some(later), failures: 2 }; SOURCES.len()];
+        let got = race_sources(&mut schedule, 1, |_| async { panic!("no source is due") }).await;
+
+        assert!(matches!(got, Fetched::NothingDue));
+        assert!(schedule.iter().all(|s| s.failures == 2 && s.next_at == Some(later)));
+    }
 }
\ No newline at end of file
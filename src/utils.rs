@@ -1,5 +1,32 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// How fractional Treasury rates become whole rupiah.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RoundingMode {
+    Nearest,
+    Floor,
+    Ceil,
+}
+
+impl RoundingMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "nearest" | "round" => Some(RoundingMode::Nearest),
+            "floor" => Some(RoundingMode::Floor),
+            "ceil" => Some(RoundingMode::Ceil),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, v: f64) -> i64 {
+        match self {
+            RoundingMode::Nearest => v.round() as i64,
+            RoundingMode::Floor => v.floor() as i64,
+            RoundingMode::Ceil => v.ceil() as i64,
+        }
+    }
+}
+
 pub fn current_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -7,10 +34,130 @@ pub fn current_timestamp() -> u64 {
         .as_secs()
 }
 
-pub fn current_wib_time() -> String {
-    let secs = current_timestamp() + 7 * 3600;
-    let d = secs % 86400;
-    format!("{:02}:{:02}:{:02}", d / 3600, (d % 3600) / 60, d % 60)
+const WIB_OFFSET_SECS: i64 = 7 * 3600;
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// `(year, month, day)` for days since 1970-01-01; inverse of `days_from_civil`.
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+/// True when `fmt` only uses the directives `format_wib` understands:
+/// `%Y %m %d %H %M %S %%`.
+pub fn valid_time_format(fmt: &str) -> bool {
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' && !matches!(chars.next(), Some('Y' | 'm' | 'd' | 'H' | 'M' | 'S' | '%')) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Renders unix seconds in WIB with a `valid_time_format` string.
+pub fn format_wib(ts: u64, fmt: &str) -> String {
+    let secs = ts as i64 + WIB_OFFSET_SECS;
+    let (y, mo, d) = civil_from_days(secs.div_euclid(86400));
+    let t = secs.rem_euclid(86400);
+    let mut out = String::with_capacity(fmt.len() + 8);
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", y)),
+            Some('m') => out.push_str(&format!("{:02}", mo)),
+            Some('d') => out.push_str(&format!("{:02}", d)),
+            Some('H') => out.push_str(&format!("{:02}", t / 3600)),
+            Some('M') => out.push_str(&format!("{:02}", (t % 3600) / 60)),
+            Some('S') => out.push_str(&format!("{:02}", t % 60)),
+            Some(other) => out.push(other),
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// Parses a Treasury `created_at` (`YYYY-MM-DD HH:MM:SS`, `T` separator,
+/// fraction, `Z` or `±HH:MM` all optional) into unix seconds.
+/// Timestamps without an offset are taken as WIB.
+pub fn parse_created_at(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let b = s.as_bytes();
+    if b.len() < 19 || b[4] != b'-' || b[7] != b'-' || (b[10] != b' ' && b[10] != b'T') {
+        return None;
+    }
+    let num = |r: std::ops::Range<usize>| -> Option<i64> {
+        let part = s.get(r)?;
+        if !part.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+
+    let (y, mo, d) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (h, mi, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&mo) || !(1..=31).contains(&d) || h > 23 || mi > 59 || sec > 60 {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    if let Some(frac) = rest.strip_prefix('.') {
+        rest = frac.trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+
+    let offset = match rest {
+        "" => WIB_OFFSET_SECS,
+        "Z" | "z" => 0,
+        tz if tz.len() == 6 && (tz.starts_with('+') || tz.starts_with('-')) => {
+            let oh: i64 = tz.get(1..3)?.parse().ok()?;
+            let om: i64 = tz.get(4..6)?.parse().ok()?;
+            let off = oh * 3600 + om * 60;
+            if tz.starts_with('-') { -off } else { off }
+        }
+        _ => return None,
+    };
+
+    let secs = days_from_civil(y, mo, d) * 86400 + h * 3600 + mi * 60 + sec - offset;
+    u64::try_from(secs).ok()
+}
+
+pub fn current_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// `ts` in WIB as `(YYYY-MM-DD HH:MM:SS, HH:MM:SS)`: the full timestamp and
+/// its short display form.
+pub fn wib_time(ts: u64) -> (String, String) {
+    (format_wib(ts, "%Y-%m-%d %H:%M:%S"), format_wib(ts, "%H:%M:%S"))
+}
+
+/// `wib_time` of now.
+pub fn current_wib_time() -> (String, String) {
+    wib_time(current_timestamp())
 }
 
 pub fn format_rupiah(n: i64) -> String {
@@ -51,6 +198,11 @@ pub fn format_diff_display(diff: i64, status: &str) -> String {
 }
 
 pub fn format_waktu_only(created_at: &str, status: &str) -> String {
+    if let Some(fmt) = crate::config::WAKTU_FORMAT.as_deref() {
+        if let Some(ts) = parse_created_at(created_at) {
+            return format!("{}{}", format_wib(ts, fmt), status);
+        }
+    }
     let time = if created_at.len() >= 19 {
         &created_at[11..19]
     } else {
@@ -59,6 +211,12 @@ pub fn format_waktu_only(created_at: &str, status: &str) -> String {
     format!("{}{}", time, status)
 }
 
+/// Formats grams with `decimals` places and `sep` as the decimal separator.
+pub fn format_gram(gram: f64, decimals: usize, sep: char) -> String {
+    let s = format!("{:.*}", decimals, gram);
+    if sep == '.' { s } else { s.replace('.', sep.encode_utf8(&mut [0; 4])) }
+}
+
 pub fn calc_profit(buy_rate: i64, sell_rate: i64, modal: i64, pokok: i64) -> String {
     if buy_rate == 0 {
         return "-".into();
@@ -66,7 +224,11 @@ pub fn calc_profit(buy_rate: i64, sell_rate: i64, modal: i64, pokok: i64) -> Str
 
     let gram = modal as f64 / buy_rate as f64;
     let val = (gram * sell_rate as f64 - pokok as f64) as i64;
-    let gram_str = format!("{:.4}", gram).replace('.', ",");
+    let gram_str = format_gram(
+        gram,
+        *crate::config::PROFIT_GRAM_DECIMALS,
+        *crate::config::PROFIT_DECIMAL_SEPARATOR,
+    );
 
     if val > 0 {
         format!("+{}🟢{}gr", format_rupiah(val), gram_str)
@@ -75,4 +237,56 @@ pub fn calc_profit(buy_rate: i64, sell_rate: i64, modal: i64, pokok: i64) -> Str
     } else {
         format!("{}➖{}gr", format_rupiah(0), gram_str)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_epochs_in_wib() {
+        let cases = [
+            (0, "1970-01-01 07:00:00", "07:00:00"),
+            (1_700_000_000, "2023-11-15 05:13:20", "05:13:20"),
+            // 17:00 UTC on the leap day is already 1 March in WIB
+            (1_709_226_000, "2024-03-01 00:00:00", "00:00:00"),
+        ];
+        for (ts, full, display) in cases {
+            assert_eq!(wib_time(ts), (full.to_string(), display.to_string()), "ts {}", ts);
+        }
+    }
+
+    #[test]
+    fn gram_precision_and_separator() {
+        let gram = 10_000_000.0 / 1_500_000.0;
+        assert_eq!(format_gram(gram, 2, '.'), "6.67");
+        assert_eq!(format_gram(gram, 2, ','), "6,67");
+        assert_eq!(format_gram(gram, 6, '.'), "6.666667");
+        assert_eq!(format_gram(gram, 6, ','), "6,666667");
+        assert_eq!(format_gram(0.5, 0, ','), "0");
+    }
+
+    #[test]
+    fn nearest_rounds_where_truncation_does_not() {
+        let cases = [
+            (1_500_000.4, 1_500_000, 1_500_000),
+            (1_500_000.5, 1_500_000, 1_500_001),
+            (1_500_000.6, 1_500_000, 1_500_001),
+        ];
+        for (v, truncated, nearest) in cases {
+            assert_eq!(v as i64, truncated, "truncation of {}", v);
+            assert_eq!(RoundingMode::Nearest.apply(v), nearest, "nearest of {}", v);
+        }
+    }
+
+    #[test]
+    fn floor_and_ceil() {
+        for v in [1_500_000.4, 1_500_000.5, 1_500_000.6] {
+            assert_eq!(RoundingMode::Floor.apply(v), 1_500_000);
+            assert_eq!(RoundingMode::Ceil.apply(v), 1_500_001);
+        }
+        assert_eq!(RoundingMode::Nearest.apply(-1.5), -2);
+        assert_eq!(RoundingMode::parse(" ROUND "), Some(RoundingMode::Nearest));
+        assert_eq!(RoundingMode::parse("truncate"), None);
+    }
 }
\ No newline at end of file
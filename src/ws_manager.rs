@@ -1,14 +1,146 @@
 use bytes::Bytes;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Semaphore, SemaphorePermit};
 
 use crate::config::*;
 use crate::state::AppState;
 
+/// Per-connection delivery format, picked with `/ws?mode=...`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WsMode {
+    /// Full state on every change (default, used by the bundled UI).
+    Full,
+    /// Versioned snapshot on connect, then RFC 6902 patches.
+    Patch,
+    /// Full state on connect, then one `compact_tick` array per gold tick.
+    Compact,
+    /// Full state on connect, then `{"type":"append","item":{...}}` per gold
+    /// tick. Other changes (USD, `limit_bulan`, coalesced ticks) resend the
+    /// full state.
+    Append,
+}
+
+impl WsMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WsMode::Full => "full",
+            WsMode::Patch => "patch",
+            WsMode::Compact => "compact",
+            WsMode::Append => "append",
+        }
+    }
+
+    pub fn parse(s: Option<&str>) -> Self {
+        match s {
+            Some("patch") => WsMode::Patch,
+            Some("compact") => WsMode::Compact,
+            Some("append") => WsMode::Append,
+            _ => WsMode::Full,
+        }
+    }
+}
+
+/// Set of update topics, picked per connection with `/ws?channels=gold,usd`
+/// and tagged on every broadcast.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Topics(u8);
+
+impl Topics {
+    /// Gold ticks and anything else that changes the gold history or profits.
+    pub const GOLD: Topics = Topics(1);
+    /// USD/IDR price changes.
+    pub const USD: Topics = Topics(2);
+    pub const ALL: Topics = Topics(3);
+
+    /// Comma-separated topic names; unknown names are ignored and an empty
+    /// result means every topic.
+    pub fn parse(s: Option<&str>) -> Self {
+        let mut bits = 0;
+        for name in s.unwrap_or("").split(',') {
+            match name.trim() {
+                "gold" => bits |= Self::GOLD.0,
+                "usd" => bits |= Self::USD.0,
+                _ => {}
+            }
+        }
+        if bits == 0 {
+            Self::ALL
+        } else {
+            Topics(bits)
+        }
+    }
+
+    pub fn union(self, other: Topics) -> Topics {
+        Topics(self.0 | other.0)
+    }
+
+    pub fn intersects(self, other: Topics) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self.0 {
+            1 => "gold",
+            2 => "usd",
+            _ => "gold,usd",
+        }
+    }
+}
+
+/// One broadcast message. `version` is the state cache version for state
+/// frames and `None` for control frames such as heartbeats.
+#[derive(Clone)]
+pub struct Frame {
+    pub version: Option<u64>,
+    /// What changed; control frames carry `Topics::ALL`.
+    pub topics: Topics,
+    pub full: Bytes,
+    /// `(from_version, patch_json)` against the previously published state.
+    pub patch: Option<(u64, Bytes)>,
+    /// `compact_tick` array, present only when the frame comes from a gold tick.
+    pub compact: Option<Bytes>,
+    /// Full state with profit tiers, present while any client asked for them.
+    pub profit: Option<Bytes>,
+    /// Gzip of `full` / `profit`, present while any client asked for `compress`.
+    pub full_gzip: Option<Bytes>,
+    pub profit_gzip: Option<Bytes>,
+    /// `AppState::usd_state`, on USD frames while any USD-only client is connected.
+    pub usd: Option<Bytes>,
+    /// Append frame for the gold tick, without / with profit tiers.
+    pub append: Option<Bytes>,
+    pub append_profit: Option<Bytes>,
+}
+
+/// Status emoji as a small integer: 0 flat, 1 up, 2 down.
+pub fn status_code(status: &str) -> u8 {
+    match status {
+        "🚀" => 1,
+        "🔻" => 2,
+        _ => 0,
+    }
+}
+
+/// Encodes a gold tick for compact clients as a bare JSON array:
+///
+/// `[buy_raw, sell_raw, diff, status_code, ts]`
+///
+/// - `buy_raw` / `sell_raw`: rates in rupiah, integers
+/// - `diff`: buy change against the previous tick
+/// - `status_code`: see [`status_code`]
+/// - `ts`: tick time in unix seconds
+pub fn compact_tick(buy: i64, sell: i64, diff: i64, status: &str, ts: u64) -> Bytes {
+    Bytes::from(format!("[{},{},{},{},{}]", buy, sell, diff, status_code(status), ts))
+}
+
 pub struct WsManager {
-    tx: broadcast::Sender<Bytes>,
+    tx: broadcast::Sender<Frame>,
     connection_count: AtomicUsize,
+    patch_clients: AtomicUsize,
+    profit_clients: AtomicUsize,
+    compress_clients: AtomicUsize,
+    usd_clients: AtomicUsize,
+    handshakes: Semaphore,
 }
 
 impl WsManager {
@@ -17,10 +149,21 @@ impl WsManager {
         Self {
             tx,
             connection_count: AtomicUsize::new(0),
+            patch_clients: AtomicUsize::new(0),
+            profit_clients: AtomicUsize::new(0),
+            compress_clients: AtomicUsize::new(0),
+            usd_clients: AtomicUsize::new(0),
+            handshakes: Semaphore::new(*WS_HANDSHAKE_CONCURRENCY),
         }
     }
 
-    pub fn subscribe(&self) -> Option<broadcast::Receiver<Bytes>> {
+    /// Queues new connections so a reconnect storm doesn't send every
+    /// initial snapshot at once. Steady-state broadcasts are not gated.
+    pub async fn handshake_permit(&self) -> Option<SemaphorePermit<'_>> {
+        self.handshakes.acquire().await.ok()
+    }
+
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Frame>> {
         let count = self.connection_count.fetch_add(1, Ordering::Relaxed);
         if count >= MAX_CONNECTIONS {
             self.connection_count.fetch_sub(1, Ordering::Relaxed);
@@ -29,12 +172,81 @@ impl WsManager {
         Some(self.tx.subscribe())
     }
 
+    /// Receiver outside the connection count, for internal probes.
+    pub fn probe(&self) -> broadcast::Receiver<Frame> {
+        self.tx.subscribe()
+    }
+
     pub fn unsubscribe(&self) {
         self.connection_count.fetch_sub(1, Ordering::Relaxed);
     }
 
     pub fn broadcast(&self, data: Bytes) {
-        let _ = self.tx.send(data);
+        let _ = self.tx.send(Frame {
+            version: None,
+            topics: Topics::ALL,
+            full: data,
+            patch: None,
+            compact: None,
+            profit: None,
+            full_gzip: None,
+            profit_gzip: None,
+            usd: None,
+            append: None,
+            append_profit: None,
+        });
+    }
+
+    pub fn broadcast_frame(&self, frame: Frame) {
+        let _ = self.tx.send(frame);
+    }
+
+    pub fn add_patch_client(&self) {
+        self.patch_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_patch_client(&self) {
+        self.patch_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn has_patch_clients(&self) -> bool {
+        self.patch_clients.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn add_profit_client(&self) {
+        self.profit_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_profit_client(&self) {
+        self.profit_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn has_profit_clients(&self) -> bool {
+        self.profit_clients.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn add_compress_client(&self) {
+        self.compress_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_compress_client(&self) {
+        self.compress_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn has_compress_clients(&self) -> bool {
+        self.compress_clients.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn add_usd_client(&self) {
+        self.usd_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_usd_client(&self) {
+        self.usd_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn has_usd_clients(&self) -> bool {
+        self.usd_clients.load(Ordering::Relaxed) > 0
     }
 
     pub fn count(&self) -> usize {
@@ -42,6 +254,15 @@ impl WsManager {
     }
 }
 
+pub async fn cadence_loop(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(*BROADCAST_CADENCE_MS));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        state.flush_pending_broadcast();
+    }
+}
+
 pub async fn heartbeat_loop(state: Arc<AppState>) {
     let ping = Bytes::from_static(b"{\"ping\":true}");
     loop {